@@ -8,10 +8,14 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 
+use std::collections::hash_map::RandomState;
 use std::env;
 use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -29,8 +33,102 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // Embed the git revision of the firmware source, so that the running firmware can report which
+    // build it is. Rebuild whenever the checked out revision or the index changes.
+    let git_hash = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=F58_GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/index");
+    if let Ok(head) = std::fs::read_to_string("../../.git/HEAD") {
+        if let Some(head_ref) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=../../.git/{}", head_ref);
+        }
+    }
+
+    // Embed when and with which compiler the firmware was built, for support. The time is when the
+    // build script last ran, which happens whenever the revision or the configuration changes.
+    // $SOURCE_DATE_EPOCH overrides it for reproducible builds.
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+    println!("cargo:rustc-env=F58_BUILD_TIME={}", build_time);
+    let rustc_version = Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned()))
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=F58_RUSTC_VERSION={}", rustc_version);
+
+    write_wifi_password(out);
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     // println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+// Writes `$F58_WIFI_PASSWORD` into `wifi_password.rs` in the output directory, to be included by
+// config.rs. If `$F58_OBFUSCATE_WIFI_PASSWORD` is `1`, the password is XORed with a random
+// per-build key, so that it does not appear in the firmware image as plain text. This only
+// prevents casual extraction: the key is stored in the image too.
+fn write_wifi_password(out: &Path) {
+    println!("cargo:rerun-if-env-changed=F58_WIFI_PASSWORD");
+    println!("cargo:rerun-if-env-changed=F58_OBFUSCATE_WIFI_PASSWORD");
+    let password = env::var("F58_WIFI_PASSWORD")
+        .expect("Set $F58_WIFI_PASSWORD to the WPA2 passphrase of the network");
+    assert!(
+        password.len() <= 64,
+        "$F58_WIFI_PASSWORD must be at most 64 bytes long"
+    );
+    let obfuscate = match env::var("F58_OBFUSCATE_WIFI_PASSWORD").as_deref() {
+        Err(_) | Ok("0") => false,
+        Ok("1") => true,
+        Ok(_) => panic!("expected 0 or 1 in $F58_OBFUSCATE_WIFI_PASSWORD"),
+    };
+
+    // RandomState is seeded randomly for every process, so every build gets a different key.
+    let mut seed = RandomState::new().build_hasher().finish() | 1;
+    let key: Vec<u8> = password
+        .bytes()
+        .map(|_| {
+            if !obfuscate {
+                return 0;
+            }
+            // xorshift64.
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        })
+        .collect();
+    let data: Vec<u8> = password.bytes().zip(&key).map(|(c, k)| c ^ k).collect();
+
+    File::create(out.join("wifi_password.rs"))
+        .unwrap()
+        .write_all(
+            format!(
+                "const WIFI_PASSWORD_DATA: &[u8] = &{:?};\n\
+                 const WIFI_PASSWORD_KEY: &[u8] = &{:?};\n",
+                data, key
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+}
@@ -0,0 +1,127 @@
+/// Tracks the phases of the boot, so that a boot that stalls can be diagnosed in the field (by the
+/// onboard LED, and by the phase timings published once MQTT is up), and reset by the watchdog.
+use core::fmt::Write;
+use embassy_rp::{peripherals, watchdog};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+use portable_atomic::{AtomicU64, AtomicU8, Ordering};
+
+// Phases of the boot, in the order they are entered.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum BootPhase {
+    // Initializing USB logging and the tasks that interact with the device.
+    Usb = 1,
+    // Uploading the firmware to the WiFi chip.
+    WifiInit,
+    // Joining the network.
+    Join,
+    // Waiting for a DHCP lease.
+    Dhcp,
+    // Connecting to the MQTT broker.
+    Mqtt,
+    // Connected to the broker for the first time.
+    Done,
+}
+
+const PHASES: [BootPhase; 6] = [
+    BootPhase::Usb,
+    BootPhase::WifiInit,
+    BootPhase::Join,
+    BootPhase::Dhcp,
+    BootPhase::Mqtt,
+    BootPhase::Done,
+];
+
+impl BootPhase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BootPhase::Usb => "usb",
+            BootPhase::WifiInit => "wifi_init",
+            BootPhase::Join => "join",
+            BootPhase::Dhcp => "dhcp",
+            BootPhase::Mqtt => "mqtt",
+            BootPhase::Done => "done",
+        }
+    }
+}
+
+// The phase the boot is in, as BootPhase; zero before the first phase is entered.
+static CURRENT_PHASE: AtomicU8 = AtomicU8::new(0);
+// When each phase was entered, in ticks.
+static PHASE_STARTS: [AtomicU64; PHASES.len()] = [const { AtomicU64::new(0) }; PHASES.len()];
+
+// Records that the boot has entered the given phase.
+pub(crate) fn enter(phase: BootPhase) {
+    PHASE_STARTS[phase as usize - 1].store(Instant::now().as_ticks(), Ordering::Relaxed);
+    CURRENT_PHASE.store(phase as u8, Ordering::Relaxed);
+    if phase != BootPhase::Done {
+        log::info!("boot phase {}: {}", phase as u8, phase.as_str());
+    }
+}
+
+// Returns the number of the phase the boot is in, or None before it starts and once it is done.
+pub(crate) fn current_phase() -> Option<u8> {
+    match CURRENT_PHASE.load(Ordering::Relaxed) {
+        0 => None,
+        phase if phase == BootPhase::Done as u8 => None,
+        phase => Some(phase),
+    }
+}
+
+// Formats when each phase was entered, in milliseconds since boot, as a JSON object. Phases that
+// were not entered are left out.
+pub(crate) fn timings_json() -> String<128> {
+    let mut s = String::new();
+    let entered = CURRENT_PHASE.load(Ordering::Relaxed) as usize;
+    s.push('{').unwrap();
+    for (i, phase) in PHASES.iter().enumerate().take(entered) {
+        let start = Instant::from_ticks(PHASE_STARTS[i].load(Ordering::Relaxed));
+        // Each entry is at most 22 bytes, so all of them always fit.
+        write!(
+            s,
+            r#"{}"{}":{}"#,
+            if i == 0 { "" } else { "," },
+            phase.as_str(),
+            start.as_millis()
+        )
+        .unwrap();
+    }
+    s.push('}').unwrap();
+    s
+}
+
+// The watchdog resets the device if it is not fed within this period, which catches a stalled
+// executor. The RP2040 supports periods up to about 8 seconds.
+const WATCHDOG_PERIOD: Duration = Duration::from_secs(5);
+const WATCHDOG_FEED_PERIOD: Duration = Duration::from_secs(1);
+
+// Feeds the watchdog, and resets the device if a boot phase has not finished in `phase_timeout`.
+// After the boot, keeps feeding the watchdog, which then only guards against a stalled executor.
+#[embassy_executor::task]
+pub(crate) async fn boot_watchdog_task(
+    watchdog: peripherals::WATCHDOG,
+    phase_timeout: Duration,
+) -> ! {
+    let mut watchdog = watchdog::Watchdog::new(watchdog);
+    // Otherwise, the watchdog would reset the device while it is halted by a debug probe.
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_PERIOD);
+    loop {
+        if let Some(phase) = current_phase() {
+            let start =
+                Instant::from_ticks(PHASE_STARTS[phase as usize - 1].load(Ordering::Relaxed));
+            if start.elapsed() > phase_timeout {
+                log::error!(
+                    "boot phase {} has not finished in {}s; resetting the device...",
+                    PHASES[phase as usize - 1].as_str(),
+                    phase_timeout.as_secs()
+                );
+                // Give the USB logger a chance to send the message above.
+                Timer::after_millis(500).await;
+                watchdog.trigger_reset();
+            }
+        }
+        watchdog.feed();
+        Timer::after(WATCHDOG_FEED_PERIOD).await;
+    }
+}
@@ -1,80 +1,1169 @@
+use embassy_rp::gpio;
+use embassy_time::Duration;
+
 /// Constructs configuration that will be built into the firmware from environment variables.
 ///
 /// Supported variables:
 ///
 /// * `$F58_WIFI_NETWORK`: SSID of the WiFi network.
 /// * `$F58_WIFI_PASSWORD`: WPA2 passphrase of the network.
-/// * `$F58_MQTT_ENDPOINT`: IPv4 address and port of the MQTT broker (in `a.b.c.d:p` form).
+/// * `$F58_OBFUSCATE_WIFI_PASSWORD`: If `1`, the passphrase is stored in the firmware XORed with a
+///   random key, rather than as plain text. This only prevents casual extraction. Defaults to `0`.
+/// * `$F58_ROAM_BELOW_DBM`: If the WiFi signal is weaker than minus this many dBm for
+///   `$F58_ROAM_AFTER_SECS`, the device looks for a stronger access point of the network and
+///   reconnects to it. `0` disables roaming. Defaults to 0.
+/// * `$F58_ROAM_AFTER_SECS`: See `$F58_ROAM_BELOW_DBM`. Defaults to 60.
+/// * `$F58_DHCP_TIMEOUT_SECS`: Time after which waiting for a DHCP lease on boot is logged as
+///   failed and counted as an attempt; the wait continues. Defaults to 30.
+/// * `$F58_DHCP_ATTEMPTS`: Number of failed attempts (see `$F58_DHCP_TIMEOUT_SECS`) after which the
+///   device falls back to `$F58_STATIC_IP` if it is set, or resets otherwise. `0` means waiting for
+///   the lease forever. Defaults to 0.
+/// * `$F58_STATIC_IP`: Address used if no DHCP lease is received, in `a.b.c.d/len` form, where
+///   `len` is the length of the network prefix. By default, there is no fallback address.
+/// * `$F58_STATIC_GATEWAY`: Gateway used with `$F58_STATIC_IP`, in `a.b.c.d` form. Only needed if
+///   the broker is in another network. By default, there is no gateway.
+/// * `$F58_STATIC_DNS`: DNS server used with `$F58_STATIC_IP`, in `a.b.c.d` form. Required if
+///   `$F58_STATIC_IP` is set and `$F58_MQTT_ENDPOINT` is a host name. By default, there is no DNS
+///   server.
+/// * `$F58_STATUS_LED`: Behaviour of the onboard LED once everything works: `on` (solid), `off`,
+///   or `blink` (a brief blink every 3 seconds), for places where a bright LED is annoying. A
+///   shorthand for `$F58_LED_PATTERN_OK`, which overrides it. Defaults to `on`.
+/// * `$F58_LED_PATTERN_OK`, `$F58_LED_PATTERN_MQTT_DOWN`, `$F58_LED_PATTERN_WIFI_CONNECTING`,
+///   `$F58_LED_PATTERN_ERROR`: Pattern of the onboard LED when everything works, when WiFi is
+///   connected but MQTT is not, while WiFi is connecting, and when joining the network fails.
+///   Written as up to 32 `0`/`1` characters, each lighting the LED for 100ms or not; the pattern
+///   repeats. Default to `1` (solid), `1111100000` (slow blink), `10` (fast blink) and
+///   `1010000000` (double blink).
+/// * `$F58_LED_BOOT_PHASES`: If `1`, while the device boots, the onboard LED blinks as many times
+///   as the number of the current boot phase, then pauses: 3 when joining the network, 4 when
+///   waiting for DHCP and 5 when connecting to the broker. The earlier phases cannot be shown, as
+///   the LED is driven by the WiFi chip. Defaults to `0`.
+/// * `$F58_MQTT_ENDPOINT`: IPv4 address or host name, and port of the MQTT broker (in `a.b.c.d:p`
+///   or `host:p` form). The port may be omitted (`a.b.c.d` or `host`), in which case the standard
+///   MQTT port 1883 is used. A host name is resolved through the DNS server from the DHCP lease
+///   once the network is up, retrying until it resolves, and again after 5 consecutive failures to
+///   connect to the broker, in case it has moved.
+/// * `$F58_BRIDGE_ENDPOINT`: Only with the `bridge` feature, where it is required: IPv4 address and
+///   port of a second MQTT broker (in `a.b.c.d:p` or `a.b.c.d` form, as `$F58_MQTT_ENDPOINT`), to
+///   which the state and availability are mirrored on a best-effort basis. Commands are not
+///   accepted from it.
+/// * `$F58_BRIDGE_USERNAME`, `$F58_BRIDGE_PASSWORD`: Only with the `bridge` feature: credentials
+///   for the broker given by `$F58_BRIDGE_ENDPOINT`. Either both or neither must be set. By
+///   default, the firmware connects to it without credentials.
+/// * `$F58_MQTT_USERNAME`, `$F58_MQTT_PASSWORD`: Credentials for the MQTT broker given by
+///   `$F58_MQTT_ENDPOINT`. Either both or neither must be set. By default, the firmware connects
+///   without credentials.
 /// * `$F58_MQTT_PREFIX`: Prefix for all MQTT topics used by the firmware. Defaults to `f58`.
+/// * `$F58_ANNOUNCE_TOPIC`: Topic to which the device periodically announces its topics, for
+///   discovery by monitoring tools. Defaults to `f58/announce/<prefix>`, so that devices with
+///   different prefixes announce on different topics.
+/// * `$F58_SHARE_GROUP`: If set, the set and cmd topics are subscribed to as MQTT5 shared
+///   subscriptions (`$share/<group>/<topic>`), so that devices sharing the prefix and the group
+///   receive each command only once between them. Commands arrive on the same topics, and the other
+///   topics are unaffected. The broker must support shared subscriptions, which minimq does not
+///   check: if commands do not arrive, look for rejected subscriptions in the broker logs. Must not
+///   contain `/`, `+` or `#`. By default, plain subscriptions are used.
+/// * `$F58_LOCATION`: Label of where the device is, for example a room name. Published to the
+///   retained location topic and included in the announcement. Must not contain `"` or `\`. By
+///   default, there is no label.
+/// * `$F58_MQTT_PROFILE`: A bundle of defaults for the MQTT options below, for common setups:
+///   * `homeassistant`: the state and the availability are published with QoS1, and the state is
+///     reasserted after the broker loses the session;
+///   * `minimal`: the state is published only when it changes and is not reasserted, and logs are
+///     batched, for the least traffic;
+///   * `debug`: the numeric state, the session state and messages on unknown topics are
+///     published, and logs are not batched.
+///
+///   The options that a profile sets can still be set individually, which overrides the profile. By
+///   default, no profile is applied, and the options have the defaults listed below.
+/// * `$F58_STATE_QOS1`: If `1`, the state is published with QoS1 instead of QoS0. Defaults to `0`.
+/// * `$F58_STATE_ON_CHANGE_ONLY`: If `1`, the state is published only when it changes, rather than
+///   also every minute. Because the state is retained, new subscribers still receive it. Defaults
+///   to `0`.
+/// * `$F58_STATE_REASSERT`: If `1`, the state is republished whenever the broker has lost the
+///   session, in case it has lost the retained state too. If `0`, the state is republished only
+///   when it differs from the last published one, to avoid load on the broker when the connection
+///   flaps. Defaults to `1`.
+/// * `$F58_OPTIMISTIC_STATE`: If `1`, the state that a set command is expected to lead to is
+///   published to the state topic as soon as the command is received, so that user interfaces
+///   respond at once. The observed state is held back until it reaches the target, or for 30s at
+///   most, and then published as usual, correcting the optimistic one if the command failed. The
+///   optimistic state is announced on the change topic with the `optimistic` source. Only the
+///   state topic is updated optimistically. Defaults to `0`.
+/// * `$F58_STATE_NUMERIC`: If `1`, the state is also published as a number to `<prefix>/state/num`,
+///   for charting: 0 = off, 1 = unknown, 10/11/12 = heating low/medium/high, 20/21/22 = on
+///   low/medium/high. Defaults to `0`.
+/// * `$F58_STATE_BYTE`: If `1`, the state is also published to `<prefix>/state/b` as a single raw
+///   byte (not an ASCII digit), for links where every byte counts. The byte has the same values as
+///   `$F58_STATE_NUMERIC`: 0x00 = off, 0x01 = unknown, 0x0a/0x0b/0x0c = heating low/medium/high,
+///   0x14/0x15/0x16 = on low/medium/high. Defaults to `0`.
+/// * `$F58_STATE_PERCENT`: If `1`, the state is also published as a percentage to
+///   `<prefix>/state/pct`, for fan-like controls such as the Home Assistant fan entity: 0 = off,
+///   33/66/100 = low/medium/high, whether heating or on. Nothing is published while the state is
+///   unknown. The same percentages are accepted on the set topic. Defaults to `0`.
+/// * `$F58_AVAILABILITY_QOS1`: If `1`, the availability (`online`, and `offline` as the will
+///   registered with the broker) is published with QoS1 instead of QoS0. Defaults to `0`.
+/// * `$F58_AVAILABILITY_RETAIN`: If `1`, the availability is retained, so that subscribers that
+///   connect later see whether the device is online. Setting it to `0` is not recommended: a
+///   warning is logged on boot. Defaults to `1`.
+/// * `$F58_QOS1_ACK_TIMEOUT_SECS`: Time after which a QoS1 publication not acknowledged by the
+///   broker is given up on. Defaults to 30.
+/// * `$F58_MQTT_SESSION_EXPIRY_SECS`: For how long the broker keeps the MQTT session (including
+///   subscriptions) after a disconnect. `0` means a clean session on every connect. Defaults to 0.
+/// * `$F58_MQTT_CONNECT_TIMEOUT_SECS`: Time after which a TCP connection attempt to the MQTT
+///   broker is given up on. Defaults to 10.
+/// * `$F58_MQTT_HANDSHAKE_TIMEOUT_SECS`: Time after which a TCP connection over which the MQTT
+///   connection was not established is dropped and reconnected. Defaults to 30.
+/// * `$F58_MQTT_RESET_GRACE_CHECKS`: Number of consecutive reads that have to find the connection
+///   unable to receive before it is treated as reset, to avoid reconnecting on momentary states. A
+///   closed connection is always treated as reset immediately. Defaults to 2.
+/// * `$F58_TARGET_RETAIN`: If `1`, the target state is published as a retained message. Defaults
+///   to `1`.
+/// * `$F58_RETAINED_SET`: What to do with the retained set message, which the broker delivers
+///   right after subscribing (on boot, and on reconnects unless the broker kept the session):
+///   * `apply`: it is applied like any other command, for automations that publish retained
+///     commands on purpose;
+///   * `ignore`: the first message on the set topic received within
+///     `$F58_IGNORE_RETAINED_SET_SECS` after subscribing is ignored, as it may be a stale command
+///     from long ago. The firmware cannot see the retain flag, so a fresh command sent within
+///     this time is ignored too.
+///
+///   Defaults to `ignore` if `$F58_IGNORE_RETAINED_SET_SECS` is set, and to `apply` otherwise.
+/// * `$F58_IGNORE_RETAINED_SET_SECS`: See `$F58_RETAINED_SET`. Only allowed with `ignore`, and must
+///   not be `0`. Defaults to 2.
+/// * `$F58_LOG_BATCH`: If `1`, queued log messages are joined with newlines and published together,
+///   rather than one message per publication. Defaults to `0`.
+/// * `$F58_LOG_UNKNOWN_TOPICS`: If `1`, every message received on a topic that the firmware does
+///   not handle is logged and answered on the error topic. Otherwise, such messages are only
+///   counted (see the `counts` command). Defaults to `0`.
+/// * `$F58_MQTT_DEBUG`: If `1`, the state of the MQTT session (the number of connections, whether
+///   the broker kept the session, and whether messages or subscriptions wait for acknowledgement)
+///   is published to the session topic whenever it changes. Defaults to `0`.
+/// * `$F58_RSSI_PERIOD_SECS`: How often the WiFi signal strength is published to the RSSI topic,
+///   independently of the state updates. `0` disables these publications. Defaults to 300.
+/// * `$F58_REACHED_TIMEOUT_SECS`: Time within which the device should reach the target state set by
+///   a command. The target is published to the reached topic once reached, or `timeout` after this
+///   time. `0` disables these publications. Defaults to 60.
+/// * `$F58_PHYSICAL_INHIBIT_SECS`: Time for which the firmware stops pushing the button after
+///   someone operates the device physically, to let them finish. After it, the target state is
+///   enforced again. `0` disables the pause. Defaults to 0.
+/// * `$F58_POWER_RAMP`: If `1`, when the device is off and commanded to the medium or high level,
+///   it is turned on at the low level first and stepped up one level at a time, to avoid thermal
+///   shock. A new command interrupts the ramp. Defaults to `0`.
+/// * `$F58_RAMP_DWELL_LOW_SECS`, `$F58_RAMP_DWELL_MEDIUM_SECS`: Time for which the power ramp (see
+///   `$F58_POWER_RAMP`) stays at the low and medium levels. Default to 60.
+/// * `$F58_FLASH_CONFIG`: If `1`, the firmware reads a config written by a provisioning tool to
+///   the last 4KiB sector of the flash, which overrides the variables above. It is a JSON object of
+///   at most 1KiB, followed by a zero byte or erased flash, with any of the fields `wifi_network`,
+///   `wifi_password`, `mqtt_endpoint` (as `$F58_MQTT_ENDPOINT`), `max_on_secs`, `deadman_secs`,
+///   `max_pushes_per_minute`, `max_reset_attempts` and `max_actuation_cycles`. Strings must not
+///   contain escape sequences. The topics cannot be changed this way, as they are built at compile
+///   time. If the config is missing or invalid, the compile-time config is used. Defaults to `0`.
+/// * `$F58_SELFCHECK`: If `1`, the firmware checks itself on boot: the LED inputs are read, the
+///   button output is pulsed for a few milliseconds (too short for the device to notice) and read
+///   back, and WiFi, DHCP and the broker are reached. The result is published to the retained
+///   selfcheck topic. Defaults to `0`.
+/// * `$F58_MAX_ON_SECS`: Maximal time the device may stay on; after it, the device is turned off
+///   unless the timer is refreshed with the `refresh` command. `0` disables the limit. Defaults
+///   to 0. Can be adjusted at runtime with the `safety maxon <secs>` command.
+/// * `$F58_DEADMAN_SECS`: Time without contact with the MQTT broker after which the device is
+///   turned off. `0` disables the dead-man's switch. Defaults to 0. Can be adjusted at runtime
+///   with the `safety deadman <secs>` command.
+/// * `$F58_FLAP_THRESHOLD`: Number of observed state changes within `$F58_FLAP_WINDOW_SECS` after
+///   which the state is considered flapping, which suggests noisy wiring, a dying LED or a device
+///   fault. The firmware then publishes the `flapping` alert. At most 32; `0` disables the
+///   detection. Defaults to 10.
+/// * `$F58_FLAP_WINDOW_SECS`: See `$F58_FLAP_THRESHOLD`. Defaults to 60.
+/// * `$F58_BOOT_PHASE_TIMEOUT_SECS`: Time after which a boot phase (initializing WiFi, joining the
+///   network, waiting for DHCP, connecting to the broker) that has not finished resets the device
+///   through the hardware watchdog. The watchdog also resets the device if the firmware hangs. The
+///   timings of the phases are published to the boot topic. `0` disables the watchdog. Defaults
+///   to 0.
+/// * `$F58_SUPPRESS_FLAPPING_STATE`: If `1`, the state is not published while it is flapping, to
+///   avoid flooding the broker. Defaults to `0`.
+/// * `$F58_MAX_RESET_ATTEMPTS`: Maximal number of consecutive attempts to reset the device from an
+///   unknown state. After them, the firmware publishes the `reset_giveup` alert and stops pushing
+///   the button until a new command or the `resume` command arrives. `0` means no limit. Defaults
+///   to 5.
+/// * `$F58_MAX_ACTUATION_CYCLES`: Maximal number of pushes made for a command without the device
+///   reaching the target state, for example because the button is not wired properly. After them,
+///   the firmware publishes the `actuation_failed` alert and stops pushing the button until a new
+///   command or the `resume` command arrives. `0` means no limit. Defaults to 6.
+/// * `$F58_MAX_PUSHES_PER_MINUTE`: Maximal number of button pushes per minute; further pushes are
+///   suppressed until the minute ends, to protect the device from command storms. `0` disables the
+///   limit. Defaults to 6.
+/// * `$F58_STATE_OVERRIDE`: If `1`, the `override <state> <secs>` command is accepted, which makes
+///   the firmware report the given state instead of the observed one, for testing automations.
+///   Defaults to `0`.
+/// * `$F58_LED_PULL_LOW`, `$F58_LED_PULL_MEDIUM`, `$F58_LED_PULL_HIGH`: Pull resistor of the input
+///   pin connected to the corresponding LED: `down`, `up` or `none`. Defaults to `down`.
+/// * `$F58_LED_ADC`: If `1`, the LEDs are read through the ADC pins (GPIO 26, 27 and 28 for the
+///   low, medium and high LEDs) instead of GPIO 12, 13 and 14, by measuring their average
+///   brightness. For LED taps through voltage dividers, or LEDs dimmed by PWM. Defaults to `0`.
+/// * `$F58_LED_ADC_DIM_THRESHOLD`, `$F58_LED_ADC_ON_THRESHOLD`: Average ADC readings (0 to 4095)
+///   from which an LED is considered dimmed and fully on. Dimmed LEDs are considered lit. Default
+///   to 400 and 2000.
+/// * `$F58_PREHEAT_FLASH_MAX_MS`: Maximal duration of the brief flash of all LEDs with which the
+///   device signals that preheating is complete. Such a flash is published as the
+///   `preheat_complete` event. `0` disables the recognition. Defaults to 1500.
+/// * `$F58_LED_BACKSTOP_POLL_SECS`: How often the LEDs are read even if no change was signalled by
+///   the GPIO interrupts, as a safety net. Defaults to 10.
+/// * `$F58_LOW_POWER_AFTER_SECS`: Time without LED changes or commands after which the device
+///   switches to the low-power mode with rarer actuation cycles and WiFi power saving. `0`
+///   disables the low-power mode. Defaults to 0.
+///
+/// The configuration is a constant, and all the values are parsed and checked when it is evaluated
+/// by the compiler. So `cargo check` with the same variables reports a misconfiguration, with the
+/// name of the variable, without flashing the firmware or any hardware.
 pub(crate) struct WifiConfig {
     pub wifi_network: &'static str,
-    pub wifi_password: &'static str,
+    pub wifi_password: StoredPassword,
+    // Time without LED changes or commands after which the WiFi chip switches to its most
+    // aggressive power saving mode, until the next one. Zero if it never does.
+    pub power_save_after: Duration,
+    // Signal strength (in dBm) below which the device roams to a stronger access point. Zero if
+    // roaming is disabled.
+    pub roam_below_rssi: i32,
+    pub roam_after: Duration,
+    pub dhcp_timeout: Duration,
+    // Zero if the device waits for a lease forever.
+    pub dhcp_attempts: u32,
+    // The address and the network prefix length used if there is no DHCP lease.
+    pub static_ip: Option<((u8, u8, u8, u8), u8)>,
+    pub static_gateway: Option<(u8, u8, u8, u8)>,
+    pub static_dns: Option<(u8, u8, u8, u8)>,
+}
+
+impl WifiConfig {
+    // Whether the broker host cannot be resolved if the static address is used, as there is no DNS
+    // server for it.
+    pub(crate) const fn needs_static_dns(&self, host: &BrokerHost) -> bool {
+        matches!(host, BrokerHost::Name(_)) && self.static_ip.is_some() && self.static_dns.is_none()
+    }
+}
+
+// A password, as stored in the firmware: XORed with the key, which is all zeros if the password is
+// not obfuscated. Written by the build script.
+pub(crate) struct StoredPassword {
+    data: &'static [u8],
+    key: &'static [u8],
+}
+
+impl StoredPassword {
+    // Returns the password as plain text.
+    pub(crate) fn reveal(&self) -> heapless::String<64> {
+        // Cannot fail: the build script or plain() checks the length, and the result is the
+        // original string.
+        let bytes = heapless::Vec::from_iter(self.data.iter().zip(self.key).map(|(c, k)| c ^ k));
+        heapless::String::from_utf8(bytes).unwrap()
+    }
+
+    // Wraps a password that is not obfuscated, such as one read at runtime. Returns None if the
+    // password is longer than the 64 characters WPA2 allows.
+    pub(crate) fn plain(password: &'static str) -> Option<Self> {
+        static ZERO_KEY: [u8; 64] = [0; 64];
+        (password.len() <= ZERO_KEY.len()).then(|| StoredPassword {
+            data: password.as_bytes(),
+            key: &ZERO_KEY,
+        })
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/wifi_password.rs"));
+
+// An on/off sequence of the onboard LED, repeated. One bit per step, the lowest bit first.
+#[derive(Clone, Copy)]
+pub(crate) struct BlinkPattern {
+    pub bits: u32,
+    // Number of steps, from 1 to 32.
+    pub len: u32,
+}
+
+// Reading the LEDs through the ADC instead of the digital inputs.
+pub(crate) struct LedAdcConfig {
+    pub enabled: bool,
+    pub dim_threshold: u16,
+    pub on_threshold: u16,
+}
+
+// Credentials for the MQTT broker. Never logged, neither over USB nor to the log topic.
+pub(crate) struct MqttAuth {
+    pub username: &'static str,
+    pub password: &'static str,
+}
+
+// Host of the MQTT broker, as configured.
+#[derive(Clone, Copy)]
+pub(crate) enum BrokerHost {
+    Address((u8, u8, u8, u8)),
+    // Resolved once the network is up, and again after repeated connection failures.
+    Name(&'static str),
+}
+
+// Stepping up through the levels when turning the device on, see $F58_POWER_RAMP.
+pub(crate) struct RampConfig {
+    pub enabled: bool,
+    // Time spent at each level before stepping up: [PowerLevel::Low, PowerLevel::Medium].
+    pub dwell: [Duration; 2],
+}
+
+// Patterns of the onboard LED for each connection status.
+pub(crate) struct StatusLedConfig {
+    pub ok: BlinkPattern,
+    pub mqtt_down: BlinkPattern,
+    pub wifi_connecting: BlinkPattern,
+    pub error: BlinkPattern,
+    // Whether the boot phase is shown instead of the patterns above until the boot is done.
+    pub boot_phases: bool,
 }
 
 // Full topic names.
 pub(crate) struct MqttTopics {
+    pub alert: &'static str,
+    pub announce: &'static str,
+    pub availability: &'static str,
+    pub broker: &'static str,
+    pub change: &'static str,
+    pub clock: &'static str,
     pub cmd: &'static str,
+    // Topic filter to subscribe to for the cmd topic.
+    pub cmd_subscription: &'static str,
+    pub cmd_counts: &'static str,
+    pub diag_all: &'static str,
+    pub echo: &'static str,
+    pub error: &'static str,
+    pub event: &'static str,
+    pub flash_writes: &'static str,
+    pub fw_hash: &'static str,
+    pub info: &'static str,
+    pub last_states: &'static str,
+    pub location: &'static str,
+    pub reached: &'static str,
+    pub mqtt_status: &'static str,
+    pub boot: &'static str,
+    pub rssi: &'static str,
+    pub trace: &'static str,
+    pub what_if: &'static str,
+    pub actuator: &'static str,
+    pub confidence: &'static str,
+    pub selfcheck: &'static str,
+    pub session: &'static str,
     pub log: &'static str,
     pub set: &'static str,
+    // Topic filter to subscribe to for the set topic.
+    pub set_subscription: &'static str,
     pub state: &'static str,
+    pub state_num: &'static str,
+    pub state_byte: &'static str,
+    pub state_pct: &'static str,
+    pub state_override: &'static str,
+    pub target: &'static str,
+}
+
+// Tunables of the MQTT client behavior.
+pub(crate) struct MqttOptions {
+    pub state_qos1: bool,
+    pub state_on_change_only: bool,
+    pub state_numeric: bool,
+    pub state_byte: bool,
+    pub state_percent: bool,
+    pub state_reassert: bool,
+    pub optimistic_state: bool,
+    // Used for both the `online` publication and the `offline` will.
+    pub availability_qos1: bool,
+    pub availability_retain: bool,
+    pub qos1_ack_timeout: Duration,
+    pub session_expiry_secs: u32,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    pub reset_grace_checks: u32,
+    pub target_retain: bool,
+    // Zero if the retained set message is applied.
+    pub ignore_retained_set: Duration,
+    // Whether the override command is accepted.
+    pub allow_state_override: bool,
+    // Zero if reaching the target is not reported.
+    pub reached_timeout: Duration,
+    pub log_batch: bool,
+    pub log_unknown_topics: bool,
+    pub debug_session: bool,
+    pub suppress_flapping_state: bool,
+    // Zero if the signal strength is not published.
+    pub rssi_period: Duration,
+}
+
+// Limits that protect the unattended heating device.
+pub(crate) struct SafetyConfig {
+    // Zero if there is no limit.
+    pub max_on: Duration,
+    // Zero if the dead-man's switch is disabled.
+    pub deadman: Duration,
+    // Zero if there is no limit.
+    pub max_pushes_per_minute: u32,
+    // Zero if there is no limit.
+    pub max_reset_attempts: u32,
+    // Zero if there is no limit.
+    pub max_actuation_cycles: u32,
 }
 
 pub(crate) struct Config {
     pub wifi_config: WifiConfig,
+    pub status_led_config: StatusLedConfig,
     pub mqtt_topics: MqttTopics,
-    pub mqtt_endpoint: ((u8, u8, u8, u8), u16),
+    pub mqtt_client_id: &'static str,
+    // None if the broker is connected without credentials.
+    pub mqtt_auth: Option<MqttAuth>,
+    // Payload published to mqtt_topics.announce.
+    pub mqtt_announcement: &'static str,
+    pub mqtt_endpoint: (BrokerHost, u16),
+    // $F58_MQTT_ENDPOINT as configured, for diagnostics.
+    pub mqtt_endpoint_str: &'static str,
+    #[cfg(feature = "bridge")]
+    pub bridge_endpoint: ((u8, u8, u8, u8), u16),
+    // None if the second broker is connected without credentials.
+    #[cfg(feature = "bridge")]
+    pub bridge_auth: Option<MqttAuth>,
+    pub mqtt_options: MqttOptions,
+    // Pulls of the LED input pins: [PowerLevel::Low, PowerLevel::Medium, PowerLevel::High].
+    pub led_pulls: [gpio::Pull; 3],
+    pub led_adc_config: LedAdcConfig,
+    // Zero if the preheat complete flash is not recognised.
+    pub preheat_flash_max: Duration,
+    pub led_backstop_poll: Duration,
+    // Zero if the actuator does not pause after physical operation of the device.
+    pub physical_inhibit: Duration,
+    pub ramp_config: RampConfig,
+    // Zero if the low-power mode is disabled.
+    pub low_power_after: Duration,
+    pub safety_config: SafetyConfig,
+    // Zero if the flap detection is disabled.
+    pub flap_threshold: u32,
+    pub flap_window: Duration,
+    // Zero if the watchdog is disabled.
+    pub boot_phase_timeout: Duration,
+    pub selfcheck: bool,
+    pub flash_config: bool,
+    // Empty if no location is configured.
+    pub location: &'static str,
+    // Git revision the firmware was built from, set by the build script.
+    pub firmware_version: &'static str,
+    // JSON object with the firmware version, the build time and the compiler version.
+    pub firmware_info: &'static str,
 }
 
+const LOW_POWER_AFTER_SECS: u64 = parse_number(option_env!("F58_LOW_POWER_AFTER_SECS"), 0);
+
+// Checked below before they are narrowed to u16, so that an out-of-range value is not truncated
+// into the range.
+const LED_ADC_DIM_THRESHOLD: u64 = parse_number(option_env!("F58_LED_ADC_DIM_THRESHOLD"), 400);
+const LED_ADC_ON_THRESHOLD: u64 = parse_number(option_env!("F58_LED_ADC_ON_THRESHOLD"), 2000);
+
+// Also checked below before they are narrowed, like the ADC thresholds.
+const MQTT_SESSION_EXPIRY_SECS: u64 = parse_number(option_env!("F58_MQTT_SESSION_EXPIRY_SECS"), 0);
+const MAX_PUSHES_PER_MINUTE: u64 = parse_number(option_env!("F58_MAX_PUSHES_PER_MINUTE"), 6);
+const ROAM_BELOW_DBM: u64 = parse_number(option_env!("F58_ROAM_BELOW_DBM"), 0);
+const MQTT_RESET_GRACE_CHECKS: u64 = parse_number(option_env!("F58_MQTT_RESET_GRACE_CHECKS"), 2);
+const MAX_RESET_ATTEMPTS: u64 = parse_number(option_env!("F58_MAX_RESET_ATTEMPTS"), 5);
+const FLAP_THRESHOLD: u64 = parse_number(option_env!("F58_FLAP_THRESHOLD"), 10);
+const DHCP_ATTEMPTS: u64 = parse_number(option_env!("F58_DHCP_ATTEMPTS"), 0);
+const MAX_ACTUATION_CYCLES: u64 = parse_number(option_env!("F58_MAX_ACTUATION_CYCLES"), 6);
+
+const MQTT_CLIENT_ID: &str = "f58mqtt";
+
+const ANNOUNCE_TOPIC: &str = if let Some(announce_topic) = option_env!("F58_ANNOUNCE_TOPIC") {
+    announce_topic
+} else {
+    // The client id is the same on every device, while the prefix tells the devices apart.
+    const_format::concatcp!("f58/announce/", MQTT_PREFIX)
+};
+
+const MQTT_ENDPOINT: &str = env!(
+    "F58_MQTT_ENDPOINT",
+    "Set $F58_MQTT_ENDPOINT to ipv4addr[:port] or host[:port] of the MQTT broker"
+);
+
 const MQTT_PREFIX: &str = if let Some(mqtt_prefix) = option_env!("F58_MQTT_PREFIX") {
     mqtt_prefix
 } else {
     "f58"
 };
 
+// Prefix of the subscribed topic filters for shared subscriptions; empty if they are not used.
+const SHARE_PREFIX: &str = if let Some(group) = option_env!("F58_SHARE_GROUP") {
+    const_format::concatcp!("$share/", group, "/")
+} else {
+    ""
+};
+
+const LOCATION: &str = if let Some(location) = option_env!("F58_LOCATION") {
+    location
+} else {
+    ""
+};
+
+// The location as a field of the announcement, which is a JSON object.
+const LOCATION_ANNOUNCEMENT: &str = if LOCATION.is_empty() {
+    ""
+} else {
+    const_format::concatcp!(r#","location":""#, LOCATION, "\"")
+};
+
 pub const CONFIG: Config = Config {
     wifi_config: WifiConfig {
         wifi_network: env!(
             "F58_WIFI_NETWORK",
             "Set $F58_WIFI_NETWORK to the network name"
         ),
-        wifi_password: env!(
-            "F58_WIFI_PASSWORD",
-            "Set $F58_WIFI_PASSWORD to the network name"
-        ),
+        wifi_password: StoredPassword {
+            data: WIFI_PASSWORD_DATA,
+            key: WIFI_PASSWORD_KEY,
+        },
+        power_save_after: Duration::from_secs(LOW_POWER_AFTER_SECS),
+        roam_below_rssi: -(ROAM_BELOW_DBM as i32),
+        roam_after: Duration::from_secs(parse_number(option_env!("F58_ROAM_AFTER_SECS"), 60)),
+        dhcp_timeout: Duration::from_secs(parse_number(option_env!("F58_DHCP_TIMEOUT_SECS"), 30)),
+        dhcp_attempts: DHCP_ATTEMPTS as u32,
+        static_ip: match option_env!("F58_STATIC_IP") {
+            Some(value) => Some(parse_ipv4(value, true)),
+            None => None,
+        },
+        static_gateway: match option_env!("F58_STATIC_GATEWAY") {
+            Some(value) => Some(parse_ipv4(value, false).0),
+            None => None,
+        },
+        static_dns: match option_env!("F58_STATIC_DNS") {
+            Some(value) => Some(parse_ipv4(value, false).0),
+            None => None,
+        },
+    },
+    status_led_config: StatusLedConfig {
+        ok: parse_blink_pattern(option_env!("F58_LED_PATTERN_OK"), STATUS_LED_OK_PATTERN),
+        mqtt_down: parse_blink_pattern(option_env!("F58_LED_PATTERN_MQTT_DOWN"), "1111100000"),
+        wifi_connecting: parse_blink_pattern(option_env!("F58_LED_PATTERN_WIFI_CONNECTING"), "10"),
+        error: parse_blink_pattern(option_env!("F58_LED_PATTERN_ERROR"), "1010000000"),
+        boot_phases: parse_flag(option_env!("F58_LED_BOOT_PHASES"), false),
     },
     mqtt_topics: MqttTopics {
+        alert: const_format::concatcp!(MQTT_PREFIX, "/alert"),
+        announce: ANNOUNCE_TOPIC,
+        availability: const_format::concatcp!(MQTT_PREFIX, "/availability"),
+        broker: const_format::concatcp!(MQTT_PREFIX, "/diag/broker"),
+        change: const_format::concatcp!(MQTT_PREFIX, "/change"),
+        clock: const_format::concatcp!(MQTT_PREFIX, "/diag/clock"),
         cmd: const_format::concatcp!(MQTT_PREFIX, "/cmd"),
+        cmd_subscription: const_format::concatcp!(SHARE_PREFIX, MQTT_PREFIX, "/cmd"),
+        cmd_counts: const_format::concatcp!(MQTT_PREFIX, "/diag/cmd_counts"),
+        diag_all: const_format::concatcp!(MQTT_PREFIX, "/diag/all"),
+        echo: const_format::concatcp!(MQTT_PREFIX, "/echo"),
+        error: const_format::concatcp!(MQTT_PREFIX, "/error"),
+        event: const_format::concatcp!(MQTT_PREFIX, "/event"),
+        flash_writes: const_format::concatcp!(MQTT_PREFIX, "/diag/flash_writes"),
+        fw_hash: const_format::concatcp!(MQTT_PREFIX, "/diag/fw_hash"),
+        info: const_format::concatcp!(MQTT_PREFIX, "/info"),
+        last_states: const_format::concatcp!(MQTT_PREFIX, "/diag/laststates"),
+        location: const_format::concatcp!(MQTT_PREFIX, "/diag/location"),
+        reached: const_format::concatcp!(MQTT_PREFIX, "/reached"),
+        mqtt_status: const_format::concatcp!(MQTT_PREFIX, "/diag/mqtt"),
+        boot: const_format::concatcp!(MQTT_PREFIX, "/diag/boot"),
+        rssi: const_format::concatcp!(MQTT_PREFIX, "/diag/rssi"),
+        trace: const_format::concatcp!(MQTT_PREFIX, "/diag/trace"),
+        what_if: const_format::concatcp!(MQTT_PREFIX, "/diag/whatif"),
+        actuator: const_format::concatcp!(MQTT_PREFIX, "/diag/actuator"),
+        confidence: const_format::concatcp!(MQTT_PREFIX, "/diag/confidence"),
+        selfcheck: const_format::concatcp!(MQTT_PREFIX, "/diag/selfcheck"),
+        session: const_format::concatcp!(MQTT_PREFIX, "/diag/session"),
         log: const_format::concatcp!(MQTT_PREFIX, "/log"),
         set: const_format::concatcp!(MQTT_PREFIX, "/set"),
+        set_subscription: const_format::concatcp!(SHARE_PREFIX, MQTT_PREFIX, "/set"),
         state: const_format::concatcp!(MQTT_PREFIX, "/state"),
+        state_num: const_format::concatcp!(MQTT_PREFIX, "/state/num"),
+        state_byte: const_format::concatcp!(MQTT_PREFIX, "/state/b"),
+        state_pct: const_format::concatcp!(MQTT_PREFIX, "/state/pct"),
+        state_override: const_format::concatcp!(MQTT_PREFIX, "/diag/override"),
+        target: const_format::concatcp!(MQTT_PREFIX, "/target"),
+    },
+    mqtt_client_id: MQTT_CLIENT_ID,
+    mqtt_auth: match (
+        option_env!("F58_MQTT_USERNAME"),
+        option_env!("F58_MQTT_PASSWORD"),
+    ) {
+        (Some(username), Some(password)) => Some(MqttAuth { username, password }),
+        (None, None) => None,
+        _ => panic!("set both $F58_MQTT_USERNAME and $F58_MQTT_PASSWORD, or neither of them"),
+    },
+    mqtt_announcement: const_format::concatcp!(
+        r#"{"prefix":""#,
+        MQTT_PREFIX,
+        r#"","topics":{"set":""#,
+        MQTT_PREFIX,
+        r#"/set","cmd":""#,
+        MQTT_PREFIX,
+        r#"/cmd","state":""#,
+        MQTT_PREFIX,
+        r#"/state","target":""#,
+        MQTT_PREFIX,
+        r#"/target","log":""#,
+        MQTT_PREFIX,
+        r#"/log","alert":""#,
+        MQTT_PREFIX,
+        r#"/alert"}"#,
+        LOCATION_ANNOUNCEMENT,
+        "}"
+    ),
+    mqtt_endpoint: match parse_broker_endpoint(MQTT_ENDPOINT) {
+        Ok(endpoint) => endpoint,
+        Err(err) => panic!("{}", err),
+    },
+    mqtt_endpoint_str: MQTT_ENDPOINT,
+    #[cfg(feature = "bridge")]
+    bridge_endpoint: parse_endpoint(env!(
+        "F58_BRIDGE_ENDPOINT",
+        "Set $F58_BRIDGE_ENDPOINT to ipv4addr[:port] of the second MQTT broker"
+    )),
+    #[cfg(feature = "bridge")]
+    bridge_auth: match (
+        option_env!("F58_BRIDGE_USERNAME"),
+        option_env!("F58_BRIDGE_PASSWORD"),
+    ) {
+        (Some(username), Some(password)) => Some(MqttAuth { username, password }),
+        (None, None) => None,
+        _ => panic!("set both $F58_BRIDGE_USERNAME and $F58_BRIDGE_PASSWORD, or neither of them"),
+    },
+    mqtt_options: MqttOptions {
+        state_qos1: parse_flag(option_env!("F58_STATE_QOS1"), MQTT_PROFILE.state_qos1),
+        state_on_change_only: parse_flag(
+            option_env!("F58_STATE_ON_CHANGE_ONLY"),
+            MQTT_PROFILE.state_on_change_only,
+        ),
+        state_numeric: parse_flag(option_env!("F58_STATE_NUMERIC"), MQTT_PROFILE.state_numeric),
+        state_byte: parse_flag(option_env!("F58_STATE_BYTE"), false),
+        state_percent: parse_flag(option_env!("F58_STATE_PERCENT"), false),
+        state_reassert: parse_flag(
+            option_env!("F58_STATE_REASSERT"),
+            MQTT_PROFILE.state_reassert,
+        ),
+        optimistic_state: parse_flag(option_env!("F58_OPTIMISTIC_STATE"), false),
+        availability_qos1: parse_flag(
+            option_env!("F58_AVAILABILITY_QOS1"),
+            MQTT_PROFILE.availability_qos1,
+        ),
+        availability_retain: parse_flag(option_env!("F58_AVAILABILITY_RETAIN"), true),
+        qos1_ack_timeout: Duration::from_secs(parse_number(
+            option_env!("F58_QOS1_ACK_TIMEOUT_SECS"),
+            30,
+        )),
+        session_expiry_secs: MQTT_SESSION_EXPIRY_SECS as u32,
+        connect_timeout: Duration::from_secs(parse_number(
+            option_env!("F58_MQTT_CONNECT_TIMEOUT_SECS"),
+            10,
+        )),
+        handshake_timeout: Duration::from_secs(parse_number(
+            option_env!("F58_MQTT_HANDSHAKE_TIMEOUT_SECS"),
+            30,
+        )),
+        reset_grace_checks: MQTT_RESET_GRACE_CHECKS as u32,
+        target_retain: parse_flag(option_env!("F58_TARGET_RETAIN"), true),
+        ignore_retained_set: IGNORE_RETAINED_SET,
+        allow_state_override: parse_flag(option_env!("F58_STATE_OVERRIDE"), false),
+        reached_timeout: Duration::from_secs(parse_number(
+            option_env!("F58_REACHED_TIMEOUT_SECS"),
+            60,
+        )),
+        log_batch: parse_flag(option_env!("F58_LOG_BATCH"), MQTT_PROFILE.log_batch),
+        log_unknown_topics: parse_flag(
+            option_env!("F58_LOG_UNKNOWN_TOPICS"),
+            MQTT_PROFILE.log_unknown_topics,
+        ),
+        debug_session: parse_flag(option_env!("F58_MQTT_DEBUG"), MQTT_PROFILE.debug_session),
+        suppress_flapping_state: parse_flag(option_env!("F58_SUPPRESS_FLAPPING_STATE"), false),
+        rssi_period: Duration::from_secs(parse_number(option_env!("F58_RSSI_PERIOD_SECS"), 300)),
     },
-    mqtt_endpoint: parse_endpoint(env!(
-        "F58_MQTT_ENDPOINT",
-        "Set $F58_MQTT_ENDPOINT to ipv4addr:port of the MQTT broker"
+    led_pulls: [
+        parse_pull(option_env!("F58_LED_PULL_LOW")),
+        parse_pull(option_env!("F58_LED_PULL_MEDIUM")),
+        parse_pull(option_env!("F58_LED_PULL_HIGH")),
+    ],
+    led_adc_config: LedAdcConfig {
+        enabled: parse_flag(option_env!("F58_LED_ADC"), false),
+        dim_threshold: LED_ADC_DIM_THRESHOLD as u16,
+        on_threshold: LED_ADC_ON_THRESHOLD as u16,
+    },
+    preheat_flash_max: Duration::from_millis(parse_number(
+        option_env!("F58_PREHEAT_FLASH_MAX_MS"),
+        1500,
+    )),
+    physical_inhibit: Duration::from_secs(parse_number(
+        option_env!("F58_PHYSICAL_INHIBIT_SECS"),
+        0,
     )),
+    ramp_config: RampConfig {
+        enabled: parse_flag(option_env!("F58_POWER_RAMP"), false),
+        dwell: [
+            Duration::from_secs(parse_number(option_env!("F58_RAMP_DWELL_LOW_SECS"), 60)),
+            Duration::from_secs(parse_number(option_env!("F58_RAMP_DWELL_MEDIUM_SECS"), 60)),
+        ],
+    },
+    led_backstop_poll: Duration::from_secs(parse_number(
+        option_env!("F58_LED_BACKSTOP_POLL_SECS"),
+        10,
+    )),
+    low_power_after: Duration::from_secs(LOW_POWER_AFTER_SECS),
+    safety_config: SafetyConfig {
+        max_on: Duration::from_secs(parse_number(option_env!("F58_MAX_ON_SECS"), 0)),
+        deadman: Duration::from_secs(parse_number(option_env!("F58_DEADMAN_SECS"), 0)),
+        max_pushes_per_minute: MAX_PUSHES_PER_MINUTE as u32,
+        max_reset_attempts: MAX_RESET_ATTEMPTS as u32,
+        max_actuation_cycles: MAX_ACTUATION_CYCLES as u32,
+    },
+    flap_threshold: FLAP_THRESHOLD as u32,
+    flap_window: Duration::from_secs(parse_number(option_env!("F58_FLAP_WINDOW_SECS"), 60)),
+    boot_phase_timeout: Duration::from_secs(parse_number(
+        option_env!("F58_BOOT_PHASE_TIMEOUT_SECS"),
+        0,
+    )),
+    selfcheck: parse_flag(option_env!("F58_SELFCHECK"), false),
+    flash_config: parse_flag(option_env!("F58_FLASH_CONFIG"), false),
+    location: LOCATION,
+    firmware_version: env!("F58_GIT_HASH"),
+    firmware_info: const_format::concatcp!(
+        r#"{"version":""#,
+        env!("F58_GIT_HASH"),
+        r#"","build_time":"#,
+        env!("F58_BUILD_TIME"),
+        r#","rustc":""#,
+        env!("F58_RUSTC_VERSION"),
+        r#""}"#
+    ),
+};
+
+// Maximal length of a topic. MQTT allows up to 65535 bytes, but every publication has to fit into
+// the minimq buffer together with its payload, so the limit is much lower.
+const MAX_TOPIC_LEN: usize = 256;
+
+// Fails the build if a topic is too long, rather than failing to subscribe or publish in runtime.
+const _: () = check_topic_lengths(&CONFIG.mqtt_topics);
+
+const _: () = assert!(
+    LED_ADC_DIM_THRESHOLD <= LED_ADC_ON_THRESHOLD && LED_ADC_ON_THRESHOLD <= 4095,
+    "expected $F58_LED_ADC_DIM_THRESHOLD <= $F58_LED_ADC_ON_THRESHOLD <= 4095"
+);
+
+// Without a DNS server, the host name of the broker cannot be resolved with the static address.
+const _: () = assert!(
+    !CONFIG.wifi_config.needs_static_dns(&CONFIG.mqtt_endpoint.0),
+    "set $F58_STATIC_DNS, as $F58_MQTT_ENDPOINT is a host name and $F58_STATIC_IP is set"
+);
+
+const _: () = assert!(
+    FLAP_THRESHOLD <= crate::state::MAX_FLAP_THRESHOLD as u64,
+    "expected at most 32 in $F58_FLAP_THRESHOLD"
+);
+
+// The numeric variables fit the types they are narrowed to.
+const _: () = assert!(
+    MQTT_SESSION_EXPIRY_SECS <= u32::MAX as u64,
+    "expected at most 4294967295 in $F58_MQTT_SESSION_EXPIRY_SECS"
+);
+const _: () = assert!(
+    MAX_PUSHES_PER_MINUTE <= u32::MAX as u64,
+    "expected at most 4294967295 in $F58_MAX_PUSHES_PER_MINUTE"
+);
+const _: () = assert!(
+    ROAM_BELOW_DBM <= i32::MAX as u64,
+    "expected at most 2147483647 in $F58_ROAM_BELOW_DBM"
+);
+const _: () = assert!(
+    MQTT_RESET_GRACE_CHECKS <= u32::MAX as u64,
+    "expected at most 4294967295 in $F58_MQTT_RESET_GRACE_CHECKS"
+);
+const _: () = assert!(
+    MAX_RESET_ATTEMPTS <= u32::MAX as u64,
+    "expected at most 4294967295 in $F58_MAX_RESET_ATTEMPTS"
+);
+const _: () = assert!(
+    DHCP_ATTEMPTS <= u32::MAX as u64,
+    "expected at most 4294967295 in $F58_DHCP_ATTEMPTS"
+);
+const _: () = assert!(
+    MAX_ACTUATION_CYCLES <= u32::MAX as u64,
+    "expected at most 4294967295 in $F58_MAX_ACTUATION_CYCLES"
+);
+
+// The share group is a single topic level.
+const _: () = if let Some(group) = option_env!("F58_SHARE_GROUP") {
+    let bytes = group.as_bytes();
+    assert!(!bytes.is_empty(), "$F58_SHARE_GROUP must not be empty");
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i] != b'/' && bytes[i] != b'+' && bytes[i] != b'#',
+            "$F58_SHARE_GROUP must not contain `/`, `+` or `#`"
+        );
+        i += 1;
+    }
+};
+
+// The location is embedded into JSON without escaping.
+const _: () = {
+    let bytes = LOCATION.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i] != b'"' && bytes[i] != b'\\' && !bytes[i].is_ascii_control(),
+            "$F58_LOCATION must not contain quotes, backslashes or control characters"
+        );
+        i += 1;
+    }
 };
 
-// Parses IPv4 endpoint in a form of `a.b.c.d:port` in compile time.
+const fn check_topic_lengths(topics: &MqttTopics) {
+    // Destructured, so that a new topic cannot be forgotten here.
+    let MqttTopics {
+        alert,
+        announce,
+        availability,
+        broker,
+        change,
+        clock,
+        cmd,
+        cmd_subscription,
+        cmd_counts,
+        diag_all,
+        echo,
+        error,
+        event,
+        flash_writes,
+        fw_hash,
+        info,
+        last_states,
+        location,
+        reached,
+        mqtt_status,
+        boot,
+        rssi,
+        trace,
+        what_if,
+        actuator,
+        confidence,
+        selfcheck,
+        session,
+        log,
+        set,
+        set_subscription,
+        state,
+        state_num,
+        state_byte,
+        state_pct,
+        state_override,
+        target,
+    } = topics;
+    let topics = [
+        alert,
+        announce,
+        availability,
+        broker,
+        change,
+        clock,
+        cmd,
+        cmd_subscription,
+        cmd_counts,
+        diag_all,
+        echo,
+        error,
+        event,
+        flash_writes,
+        fw_hash,
+        info,
+        last_states,
+        location,
+        reached,
+        mqtt_status,
+        boot,
+        rssi,
+        trace,
+        what_if,
+        actuator,
+        confidence,
+        selfcheck,
+        session,
+        log,
+        set,
+        set_subscription,
+        state,
+        state_num,
+        state_byte,
+        state_pct,
+        state_override,
+        target,
+    ];
+    let mut i = 0;
+    while i < topics.len() {
+        assert!(
+            topics[i].len() <= MAX_TOPIC_LEN,
+            "MQTT topic is too long; use a shorter $F58_MQTT_PREFIX or $F58_ANNOUNCE_TOPIC"
+        );
+        i += 1;
+    }
+}
+
+// Parses an optional non-negative decimal number in compile time.
+const fn parse_number(value: Option<&str>, default: u64) -> u64 {
+    let Some(value) = value else {
+        return default;
+    };
+    let bytes = value.as_bytes();
+    assert!(!bytes.is_empty(), "empty numeric $F58_* variable");
+
+    let mut result = 0u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "unexpected character in numeric $F58_* variable"
+        );
+        result = result * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    result
+}
+
+// Defaults of the MQTT options, selected by $F58_MQTT_PROFILE.
+struct MqttProfile {
+    state_qos1: bool,
+    state_on_change_only: bool,
+    state_numeric: bool,
+    state_reassert: bool,
+    availability_qos1: bool,
+    log_batch: bool,
+    log_unknown_topics: bool,
+    debug_session: bool,
+}
+
+const MQTT_PROFILE: MqttProfile = parse_mqtt_profile(option_env!("F58_MQTT_PROFILE"));
+
+// Parses an optional MQTT profile name in compile time. Without a profile, every option has its
+// own default.
+const fn parse_mqtt_profile(value: Option<&str>) -> MqttProfile {
+    const NONE: MqttProfile = MqttProfile {
+        state_qos1: false,
+        state_on_change_only: false,
+        state_numeric: false,
+        state_reassert: true,
+        availability_qos1: false,
+        log_batch: false,
+        log_unknown_topics: false,
+        debug_session: false,
+    };
+    match value {
+        None => NONE,
+        Some(value) => match value.as_bytes() {
+            b"homeassistant" => MqttProfile {
+                state_qos1: true,
+                availability_qos1: true,
+                ..NONE
+            },
+            b"minimal" => MqttProfile {
+                state_on_change_only: true,
+                state_reassert: false,
+                log_batch: true,
+                ..NONE
+            },
+            b"debug" => MqttProfile {
+                state_numeric: true,
+                log_unknown_topics: true,
+                debug_session: true,
+                ..NONE
+            },
+            _ => panic!("expected homeassistant, minimal or debug in $F58_MQTT_PROFILE"),
+        },
+    }
+}
+
+// Parses an optional `0`/`1` flag in compile time.
+const fn parse_flag(value: Option<&str>, default: bool) -> bool {
+    match value {
+        None => default,
+        Some(value) => match value.as_bytes() {
+            b"0" => false,
+            b"1" => true,
+            _ => panic!("expected 0 or 1 in a flag $F58_* variable"),
+        },
+    }
+}
+
+// Parses an optional `down`/`up`/`none` pull direction in compile time. Defaults to pull down.
+const fn parse_pull(value: Option<&str>) -> gpio::Pull {
+    match value {
+        None => gpio::Pull::Down,
+        Some(value) => match value.as_bytes() {
+            b"down" => gpio::Pull::Down,
+            b"up" => gpio::Pull::Up,
+            b"none" => gpio::Pull::None,
+            _ => panic!("expected down, up or none in $F58_LED_PULL_* variable"),
+        },
+    }
+}
+
+// Parses an optional blink pattern of `0`/`1` characters in compile time.
+const fn parse_blink_pattern(value: Option<&str>, default: &str) -> BlinkPattern {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => default.as_bytes(),
+    };
+    assert!(
+        !bytes.is_empty() && bytes.len() <= 32,
+        "expected 1 to 32 characters in $F58_LED_PATTERN_* variable"
+    );
+
+    let mut bits = 0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'0' => {}
+            b'1' => bits |= 1 << i,
+            _ => panic!("expected 0 or 1 in $F58_LED_PATTERN_* variable"),
+        }
+        i += 1;
+    }
+    BlinkPattern {
+        bits,
+        len: bytes.len() as u32,
+    }
+}
+
+// For how long after subscribing a set message is taken for the retained one and ignored, selected
+// by $F58_RETAINED_SET. Zero if the retained set message is applied.
+const IGNORE_RETAINED_SET: Duration = {
+    let window = option_env!("F58_IGNORE_RETAINED_SET_SECS");
+    let ignore = match option_env!("F58_RETAINED_SET") {
+        None => window.is_some(),
+        Some(value) => match value.as_bytes() {
+            b"apply" => false,
+            b"ignore" => true,
+            _ => panic!("expected apply or ignore in $F58_RETAINED_SET"),
+        },
+    };
+    assert!(
+        ignore || window.is_none(),
+        "$F58_IGNORE_RETAINED_SET_SECS is only allowed with $F58_RETAINED_SET=ignore"
+    );
+    if ignore {
+        let secs = parse_number(window, 2);
+        assert!(secs != 0, "$F58_IGNORE_RETAINED_SET_SECS must not be 0");
+        Duration::from_secs(secs)
+    } else {
+        Duration::from_ticks(0)
+    }
+};
+
+// The default pattern of the onboard LED when everything works, selected by $F58_STATUS_LED.
+const STATUS_LED_OK_PATTERN: &str = match option_env!("F58_STATUS_LED") {
+    None => "1",
+    Some(value) => match value.as_bytes() {
+        b"on" => "1",
+        b"off" => "0",
+        b"blink" => "100000000000000000000000000000",
+        _ => panic!("expected on, off or blink in $F58_STATUS_LED"),
+    },
+};
+
+// Port used if $F58_MQTT_ENDPOINT does not specify one.
+const DEFAULT_MQTT_PORT: u64 = 1883;
+
+// Parses IPv4 endpoint in a form of `a.b.c.d:port` or `a.b.c.d` (meaning DEFAULT_MQTT_PORT) in
+// compile time.
 const fn parse_endpoint(endpoint: &str) -> ((u8, u8, u8, u8), u16) {
+    match try_parse_endpoint(endpoint) {
+        Ok(endpoint) => endpoint,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+// Like parse_endpoint(), but returns an error instead of panicking, so that it can also parse
+// endpoints at runtime.
+const fn try_parse_endpoint(endpoint: &str) -> Result<((u8, u8, u8, u8), u16), &'static str> {
     let bytes = endpoint.as_bytes();
     let mut parts = [0u64; 5];
 
     let mut i = 0;
     let mut part_idx = 0;
     while i < bytes.len() {
-        if bytes[i] == b'.' || bytes[i] == b':' {
+        if bytes[i] == b'.' {
             part_idx += 1;
-            assert!(part_idx <= 4);
+            if part_idx > 3 {
+                return Err("too many octets in $F58_MQTT_ENDPOINT");
+            }
+        } else if bytes[i] == b':' {
+            if part_idx != 3 {
+                return Err("expected 4 octets before the port in $F58_MQTT_ENDPOINT");
+            }
+            part_idx = 4;
         } else if bytes[i].is_ascii_digit() {
             parts[part_idx] = parts[part_idx] * 10 + (bytes[i] - b'0') as u64;
+            if parts[part_idx] >= 65536 {
+                return Err("number too large in $F58_MQTT_ENDPOINT");
+            }
         } else {
-            panic!("unexpected character in $F58_MQTT_ENDPOINT");
+            return Err("unexpected character in $F58_MQTT_ENDPOINT");
         }
         i += 1;
     }
+    if part_idx < 3 {
+        return Err("expected 4 octets in $F58_MQTT_ENDPOINT");
+    }
+    if part_idx == 3 {
+        parts[4] = DEFAULT_MQTT_PORT;
+    }
+    if parts[4] == 0 {
+        return Err("port in $F58_MQTT_ENDPOINT must not be zero");
+    }
+    if parts[0] >= 256 || parts[1] >= 256 || parts[2] >= 256 || parts[3] >= 256 {
+        return Err("octet too large in $F58_MQTT_ENDPOINT");
+    }
+    Ok((
+        (
+            parts[0] as u8,
+            parts[1] as u8,
+            parts[2] as u8,
+            parts[3] as u8,
+        ),
+        parts[4] as u16,
+    ))
+}
+
+// Parses an IPv4 address in `a.b.c.d` form in compile time, followed by `/len` with the network
+// prefix length if `with_prefix` is set.
+const fn parse_ipv4(value: &str, with_prefix: bool) -> ((u8, u8, u8, u8), u8) {
+    let bytes = value.as_bytes();
+    let mut parts = [0u64; 5];
 
+    let mut i = 0;
+    let mut part_idx = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'.' {
+            part_idx += 1;
+            assert!(part_idx <= 3, "too many octets in $F58_STATIC_* variable");
+        } else if bytes[i] == b'/' && with_prefix {
+            assert!(
+                part_idx == 3,
+                "expected 4 octets before the prefix length in $F58_STATIC_IP"
+            );
+            part_idx = 4;
+        } else if bytes[i].is_ascii_digit() {
+            parts[part_idx] = parts[part_idx] * 10 + (bytes[i] - b'0') as u64;
+        } else {
+            panic!("unexpected character in $F58_STATIC_* variable");
+        }
+        i += 1;
+    }
+    assert!(
+        part_idx == if with_prefix { 4 } else { 3 },
+        "expected a.b.c.d/len in $F58_STATIC_IP, and a.b.c.d in $F58_STATIC_GATEWAY and \
+         $F58_STATIC_DNS"
+    );
     assert!(
-        parts[0] < 256 && parts[1] < 256 && parts[2] < 256 && parts[3] < 256 && parts[4] < 65536
+        parts[0] < 256 && parts[1] < 256 && parts[2] < 256 && parts[3] < 256 && parts[4] <= 32,
+        "expected octets up to 255 and a prefix length up to 32 in $F58_STATIC_* variable"
     );
     (
         (
@@ -83,6 +1172,103 @@ const fn parse_endpoint(endpoint: &str) -> ((u8, u8, u8, u8), u16) {
             parts[2] as u8,
             parts[3] as u8,
         ),
-        parts[4] as u16,
+        parts[4] as u8,
     )
 }
+
+const _: () = assert!(matches!(
+    parse_ipv4("192.168.1.20/24", true),
+    ((192, 168, 1, 20), 24)
+));
+const _: () = assert!(matches!(
+    parse_ipv4("192.168.1.1", false),
+    ((192, 168, 1, 1), 0)
+));
+
+// Parses the broker endpoint in a form of `a.b.c.d:port` or `host:port`, with the port optional as
+// in parse_endpoint(). The host is an address if it parses as four octets, and a name otherwise.
+pub(crate) const fn parse_broker_endpoint(
+    endpoint: &'static str,
+) -> Result<(BrokerHost, u16), &'static str> {
+    let address_err = match try_parse_endpoint(endpoint) {
+        Ok((address, port)) => return Ok((BrokerHost::Address(address), port)),
+        Err(err) => err,
+    };
+
+    let bytes = endpoint.as_bytes();
+    let mut host_len = 0;
+    while host_len < bytes.len() && bytes[host_len] != b':' {
+        host_len += 1;
+    }
+    let (host, port) = bytes.split_at(host_len);
+    if host.is_empty() || host.len() > 253 {
+        return Err("expected 1 to 253 characters in the host name in $F58_MQTT_ENDPOINT");
+    }
+    let mut numeric = true;
+    let mut i = 0;
+    while i < host.len() {
+        let c = host[i];
+        if !(c.is_ascii_digit() || c == b'.') {
+            numeric = false;
+        }
+        if !(c.is_ascii_alphanumeric() || c == b'-' || c == b'.') {
+            return Err("unexpected character in the host name in $F58_MQTT_ENDPOINT");
+        }
+        i += 1;
+    }
+    // Digits and dots only cannot be a name, so it is a mistyped address.
+    if numeric {
+        return Err(address_err);
+    }
+
+    let port = if port.is_empty() {
+        DEFAULT_MQTT_PORT
+    } else {
+        if port.len() == 1 {
+            return Err("expected a port after the colon in $F58_MQTT_ENDPOINT");
+        }
+        let mut value = 0u64;
+        // Skip the colon.
+        let mut i = 1;
+        while i < port.len() {
+            if !port[i].is_ascii_digit() {
+                return Err("unexpected character in the port in $F58_MQTT_ENDPOINT");
+            }
+            value = value * 10 + (port[i] - b'0') as u64;
+            if value >= 65536 {
+                return Err("port in $F58_MQTT_ENDPOINT is too large");
+            }
+            i += 1;
+        }
+        value
+    };
+    if port == 0 {
+        return Err("port in $F58_MQTT_ENDPOINT must not be zero");
+    }
+    match core::str::from_utf8(host) {
+        Ok(host) => Ok((BrokerHost::Name(host), port as u16)),
+        Err(_) => Err("the host name in $F58_MQTT_ENDPOINT is not valid UTF-8"),
+    }
+}
+
+// The broker endpoint is an address if it parses as one, and a name otherwise.
+const _: () = assert!(matches!(
+    parse_broker_endpoint("1.2.3.4:8883"),
+    Ok((BrokerHost::Address((1, 2, 3, 4)), 8883))
+));
+const _: () = assert!(matches!(
+    parse_broker_endpoint("mqtt.home.lan:8883"),
+    Ok((BrokerHost::Name(_), 8883))
+));
+const _: () = assert!(matches!(
+    parse_broker_endpoint("broker"),
+    Ok((BrokerHost::Name(_), 1883))
+));
+const _: () = assert!(parse_broker_endpoint("1.2.3:1883").is_err());
+
+// Both supported endpoint forms, checked in compile time.
+const _: () = assert!(matches!(parse_endpoint("1.2.3.4"), ((1, 2, 3, 4), 1883)));
+const _: () = assert!(matches!(
+    parse_endpoint("1.2.3.4:8883"),
+    ((1, 2, 3, 4), 8883)
+));
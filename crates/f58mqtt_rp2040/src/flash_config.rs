@@ -0,0 +1,286 @@
+/// Reads the configuration written to flash by a provisioning tool, so that many devices can run
+/// the same firmware build. Fields present in the flash config override the compile-time ones.
+/// Also guards the writes to flash, which wear it out.
+use crate::config::{self, Config, StoredPassword, CONFIG};
+use crate::state;
+use core::cell::RefCell;
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_rp::peripherals;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::{Duration, Instant};
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+use serde::Deserialize;
+use static_cell::StaticCell;
+
+// Size of the flash of the Pico W.
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+// The config is stored in the last sector of the flash, which memory.x keeps out of the firmware.
+const FLASH_CONFIG_OFFSET: u32 = FLASH_SIZE as u32 - FLASH_CONFIG_SECTOR_SIZE;
+// Size of the sector the config is stored in.
+const FLASH_CONFIG_SECTOR_SIZE: u32 = 4096;
+// Maximal length of the config. It ends at the first erased (0xFF) or zero byte.
+const MAX_FLASH_CONFIG_LEN: usize = 1024;
+
+type ConfigFlash = Flash<'static, peripherals::FLASH, Blocking, FLASH_SIZE>;
+
+// The flash, kept after the config is loaded for reload() and erase(). None if the flash config is
+// disabled.
+static FLASH: BlockingMutex<ThreadModeRawMutex, RefCell<Option<ConfigFlash>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+// Regions of the flash that the firmware writes to. Every write goes through write(), which counts
+// it and limits how often each region is written.
+#[derive(Clone, Copy)]
+pub(crate) enum FlashRegion {
+    // The sector with the config.
+    Config,
+}
+
+impl FlashRegion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FlashRegion::Config => "config",
+        }
+    }
+}
+
+const FLASH_REGIONS: usize = 1;
+
+// Minimal time between two writes to the same region, so that a command repeated by a broken
+// client cannot wear the flash out.
+const MIN_WRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Number of writes (erases included) since boot, and of the writes refused by the interval.
+static FLASH_WRITES: AtomicU32 = AtomicU32::new(0);
+static FLASH_WRITES_LIMITED: AtomicU32 = AtomicU32::new(0);
+// When each region was last written, in ticks, indexed by FlashRegion. Zero if it was not written
+// since boot.
+static LAST_WRITE_AT: [AtomicU64; FLASH_REGIONS] = [const { AtomicU64::new(0) }; FLASH_REGIONS];
+
+// Whether a region last written at `last_write` (None if never) may be written at `now`, all in
+// ticks.
+const fn write_allowed(last_write: Option<u64>, now: u64, min_interval: u64) -> bool {
+    match last_write {
+        None => true,
+        Some(last_write) => now.saturating_sub(last_write) >= min_interval,
+    }
+}
+
+// A region can be written once right away, and then once the interval has passed since the last
+// write.
+const _: () = {
+    let interval = 60_000;
+    assert!(write_allowed(None, 0, interval));
+    assert!(write_allowed(None, 5, interval));
+    assert!(!write_allowed(Some(1000), 1000, interval));
+    assert!(!write_allowed(Some(1000), 1000 + interval - 1, interval));
+    assert!(write_allowed(Some(1000), 1000 + interval, interval));
+    assert!(write_allowed(Some(1000), u64::MAX, interval));
+    // A clock that appears to go back does not allow a write.
+    assert!(!write_allowed(Some(1000), 999, interval));
+    assert!(write_allowed(Some(1000), 1000, 0));
+};
+
+// Makes the write to the region, unless the region was written less than MIN_WRITE_INTERVAL ago,
+// and counts it. A write that fails still counts, as it may have worn the flash.
+fn write(
+    region: FlashRegion,
+    op: impl FnOnce(&mut ConfigFlash) -> Result<(), &'static str>,
+) -> Result<(), &'static str> {
+    FLASH.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        let flash = flash.as_mut().ok_or("the flash config is disabled")?;
+        let last_write_at = &LAST_WRITE_AT[region as usize];
+        let now = Instant::now().as_ticks();
+        let last_write = match last_write_at.load(Ordering::Relaxed) {
+            0 => None,
+            ticks => Some(ticks),
+        };
+        if !write_allowed(last_write, now, MIN_WRITE_INTERVAL.as_ticks()) {
+            FLASH_WRITES_LIMITED.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "not writing the {} flash region: it was written less than {}s ago",
+                region.as_str(),
+                MIN_WRITE_INTERVAL.as_secs()
+            );
+            return Err("the flash was written too recently; try again later");
+        }
+        last_write_at.store(now.max(1), Ordering::Relaxed);
+        FLASH_WRITES.fetch_add(1, Ordering::Relaxed);
+        op(flash)
+    })
+}
+
+// Returns the number of flash writes since boot, and of the writes refused as too frequent.
+pub(crate) fn write_counts() -> (u32, u32) {
+    (
+        FLASH_WRITES.load(Ordering::Relaxed),
+        FLASH_WRITES_LIMITED.load(Ordering::Relaxed),
+    )
+}
+
+// The config as stored in flash: a JSON object, with all fields optional. Strings must not contain
+// escape sequences.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlashConfig<'a> {
+    wifi_network: Option<&'a str>,
+    wifi_password: Option<&'a str>,
+    // In the same form as $F58_MQTT_ENDPOINT.
+    mqtt_endpoint: Option<&'a str>,
+    max_on_secs: Option<u64>,
+    deadman_secs: Option<u64>,
+    max_pushes_per_minute: Option<u32>,
+    max_reset_attempts: Option<u32>,
+    max_actuation_cycles: Option<u32>,
+}
+
+// Returns CONFIG with the fields present in the flash config overridden. If there is no flash
+// config, or it is invalid, logs why and returns CONFIG as is.
+pub(crate) fn load(flash: peripherals::FLASH) -> &'static Config {
+    static DATA: StaticCell<[u8; MAX_FLASH_CONFIG_LEN]> = StaticCell::new();
+    static LOADED: StaticCell<Config> = StaticCell::new();
+
+    let mut flash = Flash::new_blocking(flash);
+    // The strings in the parsed config borrow from it.
+    let data = read(&mut flash, DATA.init([0; MAX_FLASH_CONFIG_LEN]));
+    FLASH.lock(|stored| stored.replace(Some(flash)));
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => {
+            log::error!("cannot read flash config: {:?}; using built-in config", err);
+            return &CONFIG;
+        }
+    };
+    if data.is_empty() {
+        log::info!("no flash config; using built-in config");
+        return &CONFIG;
+    }
+    let flash_config = match serde_json_core::from_slice::<FlashConfig>(data) {
+        Ok((flash_config, _)) => flash_config,
+        Err(err) => {
+            log::error!(
+                "cannot parse flash config: {:?}; using built-in config",
+                err
+            );
+            return &CONFIG;
+        }
+    };
+    match apply(flash_config) {
+        Ok(config) => {
+            log::info!("using flash config");
+            LOADED.init(config)
+        }
+        Err(err) => {
+            log::error!("invalid flash config: {}; using built-in config", err);
+            &CONFIG
+        }
+    }
+}
+
+// Reads the config into `data`, and returns it without the terminator. Empty if there is no config.
+fn read<'a>(
+    flash: &mut ConfigFlash,
+    data: &'a mut [u8; MAX_FLASH_CONFIG_LEN],
+) -> Result<&'a [u8], embassy_rp::flash::Error> {
+    flash.blocking_read(FLASH_CONFIG_OFFSET, data)?;
+    let len = data
+        .iter()
+        .position(|&b| b == 0xFF || b == 0)
+        .unwrap_or(data.len());
+    Ok(&data[..len])
+}
+
+// Re-reads the config, and applies the fields that can change at runtime: the safety limits, as if
+// they were set with the safety command. The other fields take effect on the next boot. Nothing is
+// applied if the config is invalid.
+pub(crate) fn reload() -> Result<(), &'static str> {
+    let mut data = [0; MAX_FLASH_CONFIG_LEN];
+    let data = read_stored(&mut data)?;
+    if data.is_empty() {
+        return Err("there is no flash config");
+    }
+    let (flash_config, _) = serde_json_core::from_slice::<FlashConfig>(data)
+        .map_err(|_| "cannot parse the flash config")?;
+    // Both are checked before either is applied.
+    let limits = [
+        (state::SafetyLimit::MaxOn, flash_config.max_on_secs),
+        (state::SafetyLimit::Deadman, flash_config.deadman_secs),
+    ];
+    for (_, secs) in limits {
+        if let Some(secs) = secs {
+            state::safety_limit_from_secs(secs)?;
+        }
+    }
+    for (limit, secs) in limits {
+        if let Some(secs) = secs {
+            state::set_safety_limit(limit, secs)?;
+        }
+    }
+    Ok(())
+}
+
+// Reads the config with the flash kept by load(), as read() does.
+fn read_stored(data: &mut [u8; MAX_FLASH_CONFIG_LEN]) -> Result<&[u8], &'static str> {
+    FLASH.lock(move |flash| {
+        let mut flash = flash.borrow_mut();
+        let flash = flash.as_mut().ok_or("the flash config is disabled")?;
+        read(flash, data).map_err(|_| "cannot read the flash config")
+    })
+}
+
+// Erases the config, so that the next boot uses the built-in config. Does not write to the flash
+// if there is no config, so that repeated factory resets (which reboot, and so lose the time of
+// the last write) do not wear it.
+pub(crate) fn erase() -> Result<(), &'static str> {
+    if read_stored(&mut [0; MAX_FLASH_CONFIG_LEN])?.is_empty() {
+        return Ok(());
+    }
+    write(FlashRegion::Config, |flash| {
+        flash
+            .blocking_erase(
+                FLASH_CONFIG_OFFSET,
+                FLASH_CONFIG_OFFSET + FLASH_CONFIG_SECTOR_SIZE,
+            )
+            .map_err(|_| "cannot erase the flash config")
+    })
+}
+
+fn apply(flash_config: FlashConfig<'static>) -> Result<Config, &'static str> {
+    let mut config = CONFIG;
+    if let Some(network) = flash_config.wifi_network {
+        config.wifi_config.wifi_network = network;
+    }
+    if let Some(password) = flash_config.wifi_password {
+        config.wifi_config.wifi_password =
+            StoredPassword::plain(password).ok_or("wifi_password is too long")?;
+    }
+    if let Some(endpoint) = flash_config.mqtt_endpoint {
+        config.mqtt_endpoint = config::parse_broker_endpoint(endpoint)?;
+        if config.wifi_config.needs_static_dns(&config.mqtt_endpoint.0) {
+            return Err("mqtt_endpoint is a host name, but there is no $F58_STATIC_DNS");
+        }
+        config.mqtt_endpoint_str = endpoint;
+    }
+    // Within the same range as the limits set at runtime.
+    let safety_config = &mut config.safety_config;
+    if let Some(secs) = flash_config.max_on_secs {
+        safety_config.max_on = state::safety_limit_from_secs(secs)
+            .map_err(|_| "max_on_secs is out of the allowed range [10, 86400]s")?;
+    }
+    if let Some(secs) = flash_config.deadman_secs {
+        safety_config.deadman = state::safety_limit_from_secs(secs)
+            .map_err(|_| "deadman_secs is out of the allowed range [10, 86400]s")?;
+    }
+    if let Some(count) = flash_config.max_pushes_per_minute {
+        safety_config.max_pushes_per_minute = count;
+    }
+    if let Some(count) = flash_config.max_reset_attempts {
+        safety_config.max_reset_attempts = count;
+    }
+    if let Some(count) = flash_config.max_actuation_cycles {
+        safety_config.max_actuation_cycles = count;
+    }
+    Ok(config)
+}
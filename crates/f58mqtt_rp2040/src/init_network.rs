@@ -2,13 +2,30 @@
 /// background tasks.
 ///
 /// Mostly copy-pasted from embassy/examples/rp/src/bin/wifi_tcp_server.rs.
-use crate::config::WifiConfig;
+use crate::boot::{self, BootPhase};
+use crate::config::{BrokerHost, StatusLedConfig, WifiConfig};
+use crate::{mqtt_log, state, status_led};
 use cyw43_pio::PioSpi;
 use embassy_executor::Spawner;
-use embassy_net::{Config, Stack, StackResources};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::{
+    Config, ConfigV4, IpAddress, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
+};
 use embassy_rp::{gpio, peripherals, pio};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use static_cell::StaticCell;
 
+// The WiFi chip control, shared between the tasks that use it after the initialization.
+pub(crate) type SharedControl = Mutex<ThreadModeRawMutex, cyw43::Control<'static>>;
+
+// Time after which the WiFi chip initialization is considered stuck.
+const WIFI_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Time between the attempts to resolve the host name of the broker.
+const DNS_RETRY_PERIOD: Duration = Duration::from_secs(10);
+
 #[embassy_executor::task]
 async fn wifi_task(
     runner: cyw43::Runner<
@@ -25,19 +42,184 @@ async fn net_task(stack: &'static Stack<cyw43::NetDriver<'static>>) -> ! {
     stack.run().await
 }
 
-// Returns the network stack once it ready (meaning: conencted and received IPv4 address from DHCP).
-// Never returns errors, as it always retries failures.
+// How often the signal strength is checked for roaming.
+const RSSI_CHECK_PERIOD: Duration = Duration::from_secs(10);
+// How much stronger (in dBm) another access point must be to roam to it. Avoids reconnecting to the
+// same access point, or flapping between access points of similar strength.
+const ROAM_HYSTERESIS_DBM: i32 = 6;
+
+// Monitors the signal strength, and reconnects to the network if it stays weak for
+// WifiConfig::roam_after and a stronger access point is available. The firmware of the WiFi chip
+// chooses the strongest access point on join.
+#[embassy_executor::task]
+async fn roaming_task(control: &'static SharedControl, wifi_config: &'static WifiConfig) -> ! {
+    let mut weak_since: Option<Instant> = None;
+    loop {
+        Timer::after(RSSI_CHECK_PERIOD).await;
+        let rssi = control.lock().await.get_rssi().await;
+        if rssi >= wifi_config.roam_below_rssi {
+            weak_since = None;
+            continue;
+        }
+        let now = Instant::now();
+        if now.duration_since(*weak_since.get_or_insert(now)) < wifi_config.roam_after {
+            continue;
+        }
+        weak_since = None;
+
+        let best =
+            strongest_access_point(&mut *control.lock().await, wifi_config.wifi_network).await;
+        match best {
+            Some(best) if best >= rssi + ROAM_HYSTERESIS_DBM => {
+                mqtt_log!(
+                    "WiFi signal is weak ({}dBm); reconnecting to an access point with {}dBm",
+                    rssi,
+                    best
+                );
+                status_led::set_wifi_up(false);
+                control.lock().await.leave().await;
+                join(control, wifi_config).await;
+                status_led::set_wifi_up(true);
+            }
+            _ => mqtt_log!(
+                "WiFi signal is weak ({}dBm), but there is no stronger access point",
+                rssi
+            ),
+        }
+    }
+}
+
+// How often the device is checked for entering or leaving the low-power mode.
+const POWER_SAVE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+// Switches the WiFi chip to its most aggressive power saving mode while the device is idle (see
+// state::is_idle()), and back as soon as it is not. The aggressive mode makes the chip sleep
+// through more beacons, which delays the incoming commands, so it is not used while the device is
+// in use.
+#[embassy_executor::task]
+async fn power_save_task(control: &'static SharedControl, idle_after: Duration) -> ! {
+    let mut idle = false;
+    loop {
+        Timer::after(POWER_SAVE_CHECK_PERIOD).await;
+        if state::is_idle(Instant::now(), idle_after) == idle {
+            continue;
+        }
+        idle = !idle;
+        log::info!(
+            "device is {}; switching wifi power saving",
+            if idle { "idle" } else { "active" }
+        );
+        control
+            .lock()
+            .await
+            .set_power_management(if idle {
+                cyw43::PowerManagementMode::SuperSave
+            } else {
+                cyw43::PowerManagementMode::PowerSave
+            })
+            .await;
+    }
+}
+
+// Scans for access points of the given network, and returns the signal strength of the strongest
+// one.
+async fn strongest_access_point(control: &mut cyw43::Control<'_>, ssid: &str) -> Option<i32> {
+    let mut scanner = control.scan(Default::default()).await;
+    let mut best: Option<i32> = None;
+    while let Some(bss) = scanner.next().await {
+        if &bss.ssid[..bss.ssid_len as usize] == ssid.as_bytes() {
+            best = best.max(Some(bss.rssi.into()));
+        }
+    }
+    best
+}
+
+// Joins the network, retrying until it succeeds. The control is locked for each attempt separately,
+// so that the status LED keeps blinking between the attempts.
+async fn join(control: &SharedControl, wifi_config: &WifiConfig) {
+    loop {
+        let result = control
+            .lock()
+            .await
+            .join_wpa2(
+                wifi_config.wifi_network,
+                &wifi_config.wifi_password.reveal(),
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                status_led::set_error(false);
+                break;
+            }
+            Err(err) => {
+                log::warn!("cannot join the network: {}; retrying...", err.status);
+                status_led::set_error(true);
+            }
+        }
+    }
+}
+
+// Waits for a DHCP lease, logging every WifiConfig::dhcp_timeout without one. After
+// WifiConfig::dhcp_attempts such timeouts, falls back to the static address, or resets the device
+// if there is none: a misconfigured network should not leave the device silently hanging.
+async fn wait_for_address(stack: &Stack<cyw43::NetDriver<'static>>, wifi_config: &WifiConfig) {
+    let mut attempts = 0;
+    while with_timeout(wifi_config.dhcp_timeout, stack.wait_config_up())
+        .await
+        .is_err()
+    {
+        attempts += 1;
+        log::warn!(
+            "no dhcp lease after {}s (attempt {}); still waiting...",
+            wifi_config.dhcp_timeout.as_secs(),
+            attempts
+        );
+        if wifi_config.dhcp_attempts == 0 || attempts < wifi_config.dhcp_attempts {
+            continue;
+        }
+        match wifi_config.static_ip {
+            Some(((a, b, c, d), prefix_len)) => {
+                mqtt_log!("No DHCP lease; falling back to the static address");
+                stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+                    address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), prefix_len),
+                    gateway: wifi_config
+                        .static_gateway
+                        .map(|(a, b, c, d)| Ipv4Address::new(a, b, c, d)),
+                    dns_servers: wifi_config
+                        .static_dns
+                        .iter()
+                        .map(|&(a, b, c, d)| Ipv4Address::new(a, b, c, d))
+                        .collect(),
+                }));
+            }
+            None => {
+                log::error!("no dhcp lease; resetting the device...");
+                // Give the USB logger a chance to send the message above.
+                Timer::after_millis(500).await;
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+    }
+}
+
+// Returns the network stack once it ready (meaning: conencted and received IPv4 address from DHCP),
+// and the WiFi chip control for reading the signal strength. Never returns errors, as it always
+// retries failures.
 #[allow(clippy::too_many_arguments)]
 pub(super) async fn init_network(
     spawner: Spawner,
-    wifi_config: &WifiConfig,
+    wifi_config: &'static WifiConfig,
+    status_led_config: &'static StatusLedConfig,
     pin_23: peripherals::PIN_23,
     pin_24: peripherals::PIN_24,
     pin_25: peripherals::PIN_25,
     pin_29: peripherals::PIN_29,
     pio0: peripherals::PIO0,
     dma_ch0: peripherals::DMA_CH0,
-) -> &'static Stack<cyw43::NetDriver<'static>> {
+) -> (
+    &'static Stack<cyw43::NetDriver<'static>>,
+    &'static SharedControl,
+) {
     // Firmware, embedded into the binary.
     let fw = include_bytes!("../../../embassy/cyw43-firmware/43439A0.bin");
     let clm = include_bytes!("../../../embassy/cyw43-firmware/43439A0_clm.bin");
@@ -56,43 +238,127 @@ pub(super) async fn init_network(
     );
     static STATE: StaticCell<cyw43::State> = StaticCell::new();
     let state = STATE.init(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
-    spawner.must_spawn(wifi_task(runner));
 
     log::info!("initializing wifi...");
-    control.init(clm).await;
+    boot::enter(BootPhase::WifiInit);
+    // Uploading the firmware over a flaky PIO SPI link may hang.
+    let (net_device, control) = match with_timeout(WIFI_INIT_TIMEOUT, async {
+        let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+        spawner.must_spawn(wifi_task(runner));
+        control.init(clm).await;
+        (net_device, control)
+    })
+    .await
+    {
+        Ok(initialized) => initialized,
+        Err(_) => {
+            // The initialization cannot be retried in place: the pins and the state were moved
+            // into the cyw43 driver. Reset the whole device instead, which also power-cycles the
+            // chip through the `pwr` pin.
+            log::error!("wifi initialization timed out; resetting the device...");
+            // Give the USB logger a chance to send the message above.
+            Timer::after_millis(500).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    };
+    static CONTROL: StaticCell<SharedControl> = StaticCell::new();
+    let control = &*CONTROL.init(Mutex::new(control));
+    spawner.must_spawn(status_led::status_led_task(control, status_led_config));
     control
+        .lock()
+        .await
         .set_power_management(cyw43::PowerManagementMode::PowerSave)
         .await;
     log::info!("wifi initialized");
 
     static STACK: StaticCell<Stack<cyw43::NetDriver<'static>>> = StaticCell::new();
-    static RESOURCES: StaticCell<StackResources<2>> = StaticCell::new();
+    // The MQTT socket, the bridge socket, and the DNS socket.
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
     let stack = &*STACK.init(Stack::new(
         net_device,
         Config::dhcpv4(Default::default()),
-        RESOURCES.init(StackResources::<2>::new()),
+        RESOURCES.init(StackResources::<3>::new()),
         0x2112_1221_2195_5659,
     ));
     spawner.must_spawn(net_task(stack));
     log::info!("joining wifi...");
-    loop {
-        match control
-            .join_wpa2(wifi_config.wifi_network, wifi_config.wifi_password)
-            .await
-        {
-            Ok(_) => break,
-            Err(err) => log::warn!("cannot join the network: {}; retrying...", err.status),
-        }
-    }
+    boot::enter(BootPhase::Join);
+    join(control, wifi_config).await;
     log::info!("wifi joined. waiting for dhcp...");
-    stack.wait_config_up().await;
+    boot::enter(BootPhase::Dhcp);
+    wait_for_address(stack, wifi_config).await;
     log::info!(
         "dhcp done; address is {}",
         stack.config_v4().unwrap().address.address()
     );
 
-    control.gpio_set(0, true).await; // LED means connected.
+    status_led::set_wifi_up(true);
+
+    if wifi_config.roam_below_rssi != 0 {
+        spawner.must_spawn(roaming_task(control, wifi_config));
+    }
+    if wifi_config.power_save_after != Duration::from_ticks(0) {
+        spawner.must_spawn(power_save_task(control, wifi_config.power_save_after));
+    }
+
+    (stack, control)
+}
+
+// Returns the address and the port of the broker, resolving its host name if it is configured by
+// name. Retries until the name resolves, as the device is of no use without the broker.
+pub(crate) async fn resolve_broker(
+    stack: &Stack<cyw43::NetDriver<'static>>,
+    (host, port): (BrokerHost, u16),
+) -> ((u8, u8, u8, u8), u16) {
+    let name = match host {
+        BrokerHost::Address(address) => return (address, port),
+        BrokerHost::Name(name) => name,
+    };
+    let mut attempt = 1u32;
+    loop {
+        log::info!("resolving {} (attempt {})...", name, attempt);
+        if let Some(address) = resolve(stack, name).await {
+            return (address, port);
+        }
+        log::warn!("retrying in {}s", DNS_RETRY_PERIOD.as_secs());
+        attempt += 1;
+        Timer::after(DNS_RETRY_PERIOD).await;
+    }
+}
+
+// Resolves the host name of the broker once more, for when it cannot be reached at the address it
+// resolved to before. None if the broker is configured by address, or if the name does not
+// resolve; the caller keeps the address it has then.
+pub(crate) async fn resolve_broker_again(
+    stack: &Stack<cyw43::NetDriver<'static>>,
+    (host, port): (BrokerHost, u16),
+) -> Option<((u8, u8, u8, u8), u16)> {
+    match host {
+        BrokerHost::Address(_) => None,
+        BrokerHost::Name(name) => {
+            log::info!("resolving {} again...", name);
+            resolve(stack, name).await.map(|address| (address, port))
+        }
+    }
+}
 
-    stack
+// Makes a single DNS query for the IPv4 address of the name. Logs why if it fails.
+async fn resolve(stack: &Stack<cyw43::NetDriver<'static>>, name: &str) -> Option<(u8, u8, u8, u8)> {
+    match stack.dns_query(name, DnsQueryType::A).await {
+        Ok(addresses) => match addresses.first() {
+            Some(IpAddress::Ipv4(address)) => {
+                log::info!("{} resolved to {}", name, address);
+                let [a, b, c, d] = address.0;
+                Some((a, b, c, d))
+            }
+            None => {
+                log::warn!("{} has no address", name);
+                None
+            }
+        },
+        Err(err) => {
+            log::warn!("cannot resolve {}: {:?}", name, err);
+            None
+        }
+    }
 }
@@ -4,27 +4,38 @@
 use core::fmt::Arguments;
 use embassy_executor::Spawner;
 use embassy_rp::{bind_interrupts, peripherals, usb};
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
+use embassy_time::Duration;
 use heapless::String;
 use panic_probe as _;
 
+mod boot;
 mod config;
+mod flash_config;
 mod init_network;
 mod mqtt;
 mod state;
+mod status_led;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ =>  embassy_rp::usb::InterruptHandler<peripherals::USB>;
     PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<peripherals::PIO0>;
 });
 
+// Level of the USB log, except in the debug mode.
+pub(crate) const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
+
 #[embassy_executor::task]
 async fn logger_task(driver: usb::Driver<'static, peripherals::USB>) {
-    embassy_usb_logger::run!(8192, log::LevelFilter::Info, driver);
+    embassy_usb_logger::run!(8192, LOG_LEVEL, driver);
 }
 
-static LOG_CHANNEL: Channel<ThreadModeRawMutex, String<256>, 16> = Channel::new();
+// The channels used for logging and alerts are protected by a critical section rather than assuming
+// thread mode, so that mqtt_log!() and mqtt_alert() can be called from any context: async tasks on
+// any executor, interrupt handlers, or the panic handler. They never block: if the channel is full,
+// the message is dropped (and still logged over USB).
+static LOG_CHANNEL: Channel<CriticalSectionRawMutex, String<256>, 16> = Channel::new();
 
 fn mqtt_log(args: Arguments<'_>) {
     let mut s = String::<256>::new();
@@ -41,6 +52,17 @@ fn mqtt_log(args: Arguments<'_>) {
     }
 }
 
+static ALERT_CHANNEL: Channel<CriticalSectionRawMutex, &'static str, 8> = Channel::new();
+
+// Publishes the given alert name to the MQTT alert topic. Used for safety-related events that
+// automations may want to react to.
+fn mqtt_alert(alert: &'static str) {
+    log::warn!("mqtt alert: {}", alert);
+    if let Err(err) = ALERT_CHANNEL.try_send(alert) {
+        log::warn!("^ the alert above was not sent to mqtt: {:?}", err);
+    }
+}
+
 // Logs the given formatted string to the MQTT log topic.
 #[macro_export]
 macro_rules! mqtt_log {
@@ -52,20 +74,65 @@ macro_rules! mqtt_log {
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
+    boot::enter(boot::BootPhase::Usb);
 
     // Init USB first, so that early debug logging is available, including logs from interacting
     // with network.
     let usb_driver = usb::Driver::new(p.USB, Irqs);
     spawner.must_spawn(logger_task(usb_driver));
 
-    // Start tasks responsible for interacting with Flair58.
-    spawner.must_spawn(state::led_detector_task(p.PIN_12, p.PIN_13, p.PIN_14));
-    spawner.must_spawn(state::state_actuator_task(p.PIN_15));
+    let config: &'static config::Config = if config::CONFIG.flash_config {
+        flash_config::load(p.FLASH)
+    } else {
+        &config::CONFIG
+    };
+
+    // Start tasks responsible for interacting with Flair58. They do not depend on the network, and
+    // keep working if WiFi never comes up: init_network() below only blocks main(), and MQTT is
+    // layered on top once it is available. Without commands, the target stays Off as set at boot
+    // (and the dead-man's switch, if enabled, keeps it there).
+    state::set_flap_limits(config.flap_threshold, config.flap_window);
+    if config.led_adc_config.enabled {
+        spawner.must_spawn(state::adc_led_detector_task(
+            p.ADC,
+            p.PIN_26,
+            p.PIN_27,
+            p.PIN_28,
+            &config.led_adc_config,
+            config.preheat_flash_max,
+        ));
+    } else {
+        spawner.must_spawn(state::led_detector_task(
+            p.PIN_12,
+            p.PIN_13,
+            p.PIN_14,
+            config.led_pulls,
+            config.preheat_flash_max,
+            config.led_backstop_poll,
+        ));
+    }
+    spawner.must_spawn(state::state_actuator_task(
+        p.PIN_15,
+        config.physical_inhibit,
+        &config.ramp_config,
+        config.low_power_after,
+        &config.safety_config,
+        config.selfcheck,
+    ));
+
+    // From here on, a boot phase that stalls resets the device.
+    if config.boot_phase_timeout != Duration::from_ticks(0) {
+        spawner.must_spawn(boot::boot_watchdog_task(
+            p.WATCHDOG,
+            config.boot_phase_timeout,
+        ));
+    }
 
     // Connect to the network.
-    let network_stack = init_network::init_network(
+    let (network_stack, wifi_control) = init_network::init_network(
         spawner,
-        &config::CONFIG.wifi_config,
+        &config.wifi_config,
+        &config.status_led_config,
         p.PIN_23,
         p.PIN_24,
         p.PIN_25,
@@ -74,18 +141,24 @@ async fn main(spawner: Spawner) {
         p.DMA_CH0,
     )
     .await;
+    boot::enter(boot::BootPhase::Mqtt);
     mqtt_log!(
         "The device has started. Address: {:?}",
         network_stack.config_v4()
     );
+    let broker_endpoint = init_network::resolve_broker(network_stack, config.mqtt_endpoint).await;
 
     // Handle MQTT incoming and outgoing messages..
     spawner.must_spawn(mqtt::minimq_task(
         network_stack,
-        &config::CONFIG.mqtt_topics,
-        config::CONFIG.mqtt_endpoint,
+        wifi_control,
+        config,
+        broker_endpoint,
         LOG_CHANNEL.receiver(),
+        ALERT_CHANNEL.receiver(),
     ));
+    #[cfg(feature = "bridge")]
+    spawner.must_spawn(mqtt::bridge_task(network_stack, config));
 
     // Once main() exists, the executor continues to run already spawned tasks forever.
 }
@@ -1,22 +1,30 @@
-use crate::mqtt_log;
+use crate::boot::{self, BootPhase};
+use crate::init_network::{self, SharedControl};
 use crate::state::{self, PowerLevel, TargetState};
-use core::cell::RefCell;
+use crate::{mqtt_log, status_led};
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
 use core::ops::DerefMut;
 use embassy_net::tcp::TcpSocket;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Receiver;
-use embassy_time::{Duration, Instant, Ticker};
-use heapless::String;
-use minimq::Publication;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use heapless::{String, Vec};
+use minimq::{Publication, QoS};
+use rand_core::RngCore;
 
 mod interop {
     /// Various helpers to ensure interoperability between Embassy's async interfaces and minimq's
     /// sync interaces.
-    use core::{cell::RefCell, cmp::min};
+    use core::{
+        cell::{Cell, RefCell},
+        cmp::min,
+    };
     use embassy_net::tcp;
-    use embassy_time::Instant;
+    use embassy_time::{with_timeout, Duration, Instant};
     use embedded_nal::{nb::Error::WouldBlock, SocketAddr, SocketAddrV4};
-    use minimq::{broker::IpBroker, Broker};
+    use minimq::Broker;
 
     #[derive(Debug)]
     #[allow(dead_code)] // Rust doesn't consider derived Debug as field access.
@@ -47,14 +55,20 @@ mod interop {
     pub(super) struct BlockingSocketStack<'sock, 'buf> {
         // The wrapped socket.
         socket: &'sock RefCell<tcp::TcpSocket<'buf>>,
-        // Remote endpoint the socket corresponds to.
-        endpoint: SocketAddr,
+        // Remote endpoint the socket corresponds to. Shared with the SharedBroker, as it changes if
+        // the host name of the broker resolves to another address.
+        endpoint: &'sock Cell<SocketAddr>,
         // Id of the socket that the stack currently emulates. Used to track that there is only one
         // active socket.
         current_socket_id: Option<SocketId>,
         // Id of the previously created socket. Incremented every time a new socket is requested, so
         // that sockets are distinguishable.
         last_socket_id: u32,
+        // Number of consecutive receive() calls that have to find the socket unable to receive
+        // before the connection is reported as reset.
+        reset_grace_checks: u32,
+        // Number of consecutive receive() calls that found the socket unable to receive.
+        cannot_recv_checks: u32,
     }
 
     // Socket type for an embedded_nal::TcpClientStack wrapper. Contains only an ID which is used
@@ -65,13 +79,16 @@ mod interop {
     impl<'sock, 'buf> BlockingSocketStack<'sock, 'buf> {
         pub(super) fn new(
             socket: &'sock RefCell<tcp::TcpSocket<'buf>>,
-            endpoint: SocketAddr,
+            endpoint: &'sock Cell<SocketAddr>,
+            reset_grace_checks: u32,
         ) -> BlockingSocketStack<'sock, 'buf> {
             BlockingSocketStack {
                 socket,
                 endpoint,
                 current_socket_id: None,
                 last_socket_id: 0,
+                reset_grace_checks,
+                cannot_recv_checks: 0,
             }
         }
 
@@ -88,27 +105,86 @@ mod interop {
         }
     }
 
-    // Ensures that the socket is connected to the given endpoint.
+    // Ensures that the socket is connected to the given endpoint. Returns whether the socket is
+    // connected; the caller backs off before retrying if not.
     pub(super) async fn ensure_connected(
         socket: &mut tcp::TcpSocket<'_>,
         endpoint: &(embassy_net::IpAddress, u16),
-    ) {
+        connect_timeout: Duration,
+    ) -> bool {
         match socket.state() {
-            tcp::State::Established => (),
+            tcp::State::Established => true,
             state => {
                 log::info!("Reopening socket; current state: {}", state);
                 // Need to reopen.
                 socket.abort();
-                if let Err(e) = socket.flush().await {
-                    log::error!("cannot flush: {:?}", e);
+                // Flushing sends the RST for the old connection. Whether it succeeded or not, only
+                // the state it left the socket in decides whether to connect.
+                match with_timeout(connect_timeout, socket.flush()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::error!("cannot flush: {:?}", e),
+                    Err(_) => log::error!("cannot flush: timed out"),
                 }
-                if let Err(e) = socket.connect(*endpoint).await {
-                    log::error!("cannot connect: {:?}", e);
+                if !may_connect(socket.state()) {
+                    log::error!(
+                        "cannot reopen socket; state after abort: {}",
+                        socket.state()
+                    );
+                    return false;
+                }
+                match with_timeout(connect_timeout, socket.connect(*endpoint)).await {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        log::error!("cannot connect: {:?}", e);
+                        false
+                    }
+                    Err(_) => {
+                        // A black-holed endpoint never answers; give up on this attempt, so that
+                        // the caller can retry later.
+                        log::error!("cannot connect: timed out");
+                        socket.abort();
+                        false
+                    }
                 }
             }
         }
     }
 
+    // Whether receive() reports the connection as reset, once `cannot_recv_checks` consecutive
+    // calls (this one included) found the socket unable to receive. A socket that is `closed` for
+    // good is reported immediately, so that the grace cannot delay a reconnect.
+    const fn reports_reset(cannot_recv_checks: u32, reset_grace_checks: u32, closed: bool) -> bool {
+        closed || cannot_recv_checks >= reset_grace_checks
+    }
+
+    // The grace of 2 calls is the default; 0 and 1 both mean no grace.
+    const _: () = {
+        assert!(!reports_reset(1, 2, false));
+        assert!(reports_reset(2, 2, false));
+        assert!(reports_reset(3, 2, false));
+        assert!(!reports_reset(4, 5, false));
+        assert!(reports_reset(1, 2, true));
+        assert!(reports_reset(1, 5, true));
+        assert!(reports_reset(1, 0, false));
+        assert!(reports_reset(1, 1, false));
+    };
+
+    // Whether a socket that was aborted and flushed may connect. Only a Closed one may, even if the
+    // flush failed, as smoltcp clears the buffers on connect; in any other state, the old
+    // connection is not torn down yet, so the socket is aborted again on the next attempt instead.
+    const fn may_connect(state_after_flush: tcp::State) -> bool {
+        matches!(state_after_flush, tcp::State::Closed)
+    }
+
+    const _: () = {
+        assert!(may_connect(tcp::State::Closed));
+        assert!(!may_connect(tcp::State::TimeWait));
+        assert!(!may_connect(tcp::State::LastAck));
+        assert!(!may_connect(tcp::State::FinWait1));
+        assert!(!may_connect(tcp::State::SynSent));
+        assert!(!may_connect(tcp::State::Established));
+    };
+
     impl<'sock, 'buf> embedded_nal::TcpClientStack for BlockingSocketStack<'sock, 'buf> {
         type Error = SocketError;
         type TcpSocket = SocketId;
@@ -118,6 +194,7 @@ mod interop {
         fn socket(&mut self) -> Result<Self::TcpSocket, Self::Error> {
             self.check_socket(None)?;
             self.last_socket_id += 1;
+            self.cannot_recv_checks = 0;
             let new_id = SocketId(self.last_socket_id);
             self.current_socket_id = Some(new_id);
             Ok(new_id)
@@ -133,10 +210,10 @@ mod interop {
         ) -> embedded_nal::nb::Result<(), Self::Error> {
             self.check_socket(Some(*socket))?;
 
-            if self.endpoint != remote {
+            if self.endpoint.get() != remote {
                 return Err(embedded_nal::nb::Error::Other(
                     SocketError::UnexpectedAddr {
-                        expected: self.endpoint,
+                        expected: self.endpoint.get(),
                         got: remote,
                     },
                 ));
@@ -186,9 +263,15 @@ mod interop {
             let mut socket = self.socket.borrow_mut();
             if !socket.may_recv() {
                 // If the server closed the socket (or the connection was closed for other reasons),
-                // report it immediately.
-                return Err(embedded_nal::nb::Error::Other(SocketError::ConnectionReset));
+                // report it once it persists for reset_grace_checks calls.
+                self.cannot_recv_checks += 1;
+                let closed = matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait);
+                if reports_reset(self.cannot_recv_checks, self.reset_grace_checks, closed) {
+                    return Err(embedded_nal::nb::Error::Other(SocketError::ConnectionReset));
+                }
+                return Err(WouldBlock);
             }
+            self.cannot_recv_checks = 0;
             if !socket.can_recv() {
                 // No data in the buffer.
                 return Err(WouldBlock);
@@ -213,6 +296,22 @@ mod interop {
         }
     }
 
+    // minimq broker whose address is read on every connection, so that it can be changed after the
+    // client is created.
+    pub(super) struct SharedBroker<'a>(pub(super) &'a Cell<SocketAddr>);
+
+    impl Broker for SharedBroker<'_> {
+        fn get_address(&mut self) -> Option<SocketAddr> {
+            Some(self.0.get())
+        }
+
+        fn set_port(&mut self, port: u16) {
+            let mut address = self.0.get();
+            address.set_port(port);
+            self.0.set(address);
+        }
+    }
+
     // Wraps embassy_time to appear as sync embedded_time::Clock.
     pub(super) struct Clock;
 
@@ -245,21 +344,17 @@ mod interop {
     }
 
     // Parses endpoint (4 octets of an IP address and a port) to all library-specific types that
-    // the parent module has to work with.
-    // Returns (embassy_net endpoint, embedded_nal endpoint, minimq endpoint).
+    // the parent module has to work with. The minimq broker is a SharedBroker of the embedded_nal
+    // endpoint.
+    // Returns (embassy_net endpoint, embedded_nal endpoint).
     pub(super) fn parse_endpoint(
         (ip, port): ((u8, u8, u8, u8), u16),
-    ) -> ((embassy_net::IpAddress, u16), SocketAddr, IpBroker) {
+    ) -> ((embassy_net::IpAddress, u16), SocketAddr) {
         let embedded_nal_ip = embedded_nal::Ipv4Addr::new(ip.0, ip.1, ip.2, ip.3);
 
         (
             (embassy_net::IpAddress::v4(ip.0, ip.1, ip.2, ip.3), port),
             SocketAddr::V4(SocketAddrV4::new(embedded_nal_ip, port)),
-            {
-                let mut broker = IpBroker::new(embedded_nal::IpAddr::V4(embedded_nal_ip));
-                broker.set_port(port);
-                broker
-            },
         )
     }
 }
@@ -267,25 +362,444 @@ mod interop {
 // A command that the device can receive over MQTT.
 #[derive(Debug)]
 enum MqttCommand {
-    Unknown,
+    // The message was not recognised as a command.
+    Rejected(RejectReason),
     Set(TargetState),
+    // A set message that arrived right after subscribing, ignored as a likely stale retained one.
+    IgnoredRetainedSet,
+    // Moves the target state one step up or down.
+    StepUp,
+    StepDown,
+    // Sets the highest level for the given number of seconds, then restores the previous target.
+    Boost(u32),
+    // Already handled in process_incoming(); only needs to be counted.
+    Ping,
+    PublishCommandCounts,
+    // Publishes the text back to the echo topic, to test the full round trip through the broker.
+    Echo(Vec<u8, ECHO_CAPACITY>),
+    // Starts or stops streaming raw LED changes to the trace topic.
+    StartTrace,
+    StopTrace,
+    // Restarts the max_on safety timer.
+    RefreshSafetyTimer,
+    // Resumes pushing the button after the actuator has given up.
+    ResumeActuation,
+    PublishLastStates,
+    // Publishes all diagnostics of the MQTT client in a single message.
+    PublishDiagnostics,
+    // Publishes what the actuator would do now, without doing it.
+    PublishWhatIf,
+    // Publishes the actuator's bookkeeping: the last push, the timers and whether it has given up.
+    PublishActuatorStatus,
+    // Publishes how close the LED timings are to the blink threshold.
+    PublishConfidence,
+    // Republishes the availability, the firmware info and the announcement right away, for example
+    // after the retained messages were cleared on the broker.
+    Reannounce,
+    // Zeroes the diagnostic counters, to measure a fresh interval.
+    ResetCounters,
+    // Adjusts the duration after which the LED is considered steady, in milliseconds.
+    SetBlinkDuration(u32),
+    // Reports the given state instead of the observed one for the given number of seconds.
+    OverrideState(state::DeviceState, u32),
+    // Adjusts a safety limit, in seconds.
+    SetSafetyLimit(state::SafetyLimit, u32),
+    // Enables the debug mode for the given number of seconds.
+    StartDebug(u32),
+    // Erases the flash config and reboots with the built-in config.
+    FactoryReset,
+    // Re-reads the flash config and applies the fields that can change at runtime.
+    ReloadFlashConfig,
+}
+
+// Why a received message was not recognised as a command.
+#[derive(Debug, Clone, Copy)]
+enum RejectReason {
+    // The message arrived on a topic that the device does not handle.
+    UnknownTopic,
+    // The message is not a command that the device understands.
+    Unparseable,
+    // The command is well-formed, but its value is outside of the allowed range.
+    OutOfRange,
+}
+
+impl RejectReason {
+    // Represents the reason as a string, for publishing in MQTT topic.
+    fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::UnknownTopic => "unknown_topic",
+            RejectReason::Unparseable => "unparseable",
+            RejectReason::OutOfRange => "out_of_range",
+        }
+    }
+}
+
+// Maximal length of the echoed text; longer texts are truncated.
+const ECHO_CAPACITY: usize = 256;
+
+// State of the MQTT session as seen by minimq, for debugging session persistence.
+#[derive(Clone, Copy, PartialEq)]
+struct SessionInfo {
+    // Number of MQTT connections established since boot.
+    connections: u32,
+    // Whether the broker has kept the session for the current connection.
+    session_present: bool,
+    // Whether there are QoS1 publications not acknowledged yet.
+    pending_messages: bool,
+    // Whether there are subscriptions not acknowledged yet.
+    subscriptions_pending: bool,
+}
+
+// Numbers of commands processed since boot, by kind.
+#[derive(Default)]
+struct CommandCounts {
+    // Commands received on the set topic.
+    set: u32,
+    // Commands received on the cmd topic.
+    cmd: u32,
+    // Messages that were rejected on the handled topics.
+    unknown: u32,
+    // Messages received on topics that the firmware does not handle.
+    unknown_topic: u32,
+}
+
+impl CommandCounts {
+    fn record(&mut self, command: &MqttCommand) {
+        match command {
+            MqttCommand::Rejected(RejectReason::UnknownTopic) => self.unknown_topic += 1,
+            MqttCommand::Rejected(_) => self.unknown += 1,
+            MqttCommand::Set(_)
+            | MqttCommand::IgnoredRetainedSet
+            | MqttCommand::StepUp
+            | MqttCommand::StepDown
+            | MqttCommand::Boost(_) => self.set += 1,
+            MqttCommand::Ping
+            | MqttCommand::PublishCommandCounts
+            | MqttCommand::Echo(_)
+            | MqttCommand::StartTrace
+            | MqttCommand::StopTrace
+            | MqttCommand::RefreshSafetyTimer
+            | MqttCommand::ResumeActuation
+            | MqttCommand::PublishLastStates
+            | MqttCommand::PublishDiagnostics
+            | MqttCommand::PublishWhatIf
+            | MqttCommand::PublishActuatorStatus
+            | MqttCommand::PublishConfidence
+            | MqttCommand::Reannounce
+            | MqttCommand::ResetCounters
+            | MqttCommand::SetBlinkDuration(_)
+            | MqttCommand::OverrideState(_, _)
+            | MqttCommand::SetSafetyLimit(_, _)
+            | MqttCommand::StartDebug(_)
+            | MqttCommand::FactoryReset
+            | MqttCommand::ReloadFlashConfig => self.cmd += 1,
+        }
+    }
+
+    // Formats the counts as a JSON object, for publishing in MQTT topic.
+    fn to_json(&self) -> String<96> {
+        let mut s = String::new();
+        // The longest possible output is 83 bytes, so it always fits.
+        write!(
+            s,
+            r#"{{"set":{},"cmd":{},"unknown":{},"unknown_topic":{}}}"#,
+            self.set, self.cmd, self.unknown, self.unknown_topic
+        )
+        .unwrap();
+        s
+    }
+}
+
+// A command accepted on the set topic. Separate from MqttCommand, which cannot be built at compile
+// time, so that the parsers can be checked below.
+#[derive(Clone, Copy)]
+enum SetCommand {
+    Set(TargetState),
+    StepUp,
+    StepDown,
+    Boost(u32),
+}
+
+impl SetCommand {
+    fn into_command(self) -> MqttCommand {
+        match self {
+            SetCommand::Set(target) => MqttCommand::Set(target),
+            SetCommand::StepUp => MqttCommand::StepUp,
+            SetCommand::StepDown => MqttCommand::StepDown,
+            SetCommand::Boost(secs) => MqttCommand::Boost(secs),
+        }
+    }
+}
+
+// Parses a command received on the set topic. The format is detected by the first byte only, so
+// the first matching rule wins:
+// * `{`: minimal JSON object with a single key, either `{"state": "<word>"}` or
+//   `{"percent": <percentage>}`, where the word is anything accepted in the last form, including
+//   `boost <secs>`. Whitespace is allowed around the braces, the key and the value;
+// * a digit: percentage, see TargetState::from_percent();
+// * anything else, including a JSON object after leading whitespace: a word, `off`, `low`,
+//   `medium`, `high`, `up` or `down`, or `boost <secs>`.
+// Except inside a JSON object, the message must not have surrounding whitespace (like a trailing
+// newline).
+const fn parse_set_command(msg: &[u8]) -> Result<SetCommand, RejectReason> {
+    match msg {
+        [b'{', ..] => parse_set_json(msg),
+        [b'0'..=b'9', ..] => match parse_percent(msg) {
+            Ok(target) => Ok(SetCommand::Set(target)),
+            Err(reason) => Err(reason),
+        },
+        _ => parse_set_word(msg),
+    }
+}
+
+// Longest accepted boost.
+const MAX_BOOST_SECS: u32 = 60 * 60;
+
+const fn parse_set_word(msg: &[u8]) -> Result<SetCommand, RejectReason> {
+    match msg {
+        b"off" => Ok(SetCommand::Set(TargetState::Off)),
+        b"low" => Ok(SetCommand::Set(TargetState::On(PowerLevel::Low))),
+        b"medium" => Ok(SetCommand::Set(TargetState::On(PowerLevel::Medium))),
+        b"high" => Ok(SetCommand::Set(TargetState::On(PowerLevel::High))),
+        b"up" => Ok(SetCommand::StepUp),
+        b"down" => Ok(SetCommand::StepDown),
+        [b'b', b'o', b'o', b's', b't', b' ', secs @ ..] => match parse_decimal(secs) {
+            Ok(secs @ 1..=MAX_BOOST_SECS) => Ok(SetCommand::Boost(secs)),
+            Ok(_) => Err(RejectReason::OutOfRange),
+            Err(reason) => Err(reason),
+        },
+        _ => Err(RejectReason::Unparseable),
+    }
+}
+
+// Parses a decimal percentage (without the `%` sign).
+const fn parse_percent(msg: &[u8]) -> Result<TargetState, RejectReason> {
+    let percent = match parse_decimal(msg) {
+        Ok(percent) => percent,
+        Err(reason) => return Err(reason),
+    };
+    if percent > u8::MAX as u32 {
+        return Err(RejectReason::OutOfRange);
+    }
+    match TargetState::from_percent(percent as u8) {
+        Some(target) => Ok(target),
+        None => Err(RejectReason::OutOfRange),
+    }
 }
 
+// Parses a non-negative decimal number. A number that does not fit into u32 is well-formed, so it
+// is out of range rather than unparseable.
+const fn parse_decimal(msg: &[u8]) -> Result<u32, RejectReason> {
+    if msg.is_empty() {
+        return Err(RejectReason::Unparseable);
+    }
+    let mut result = 0u32;
+    let mut overflow = false;
+    let mut i = 0;
+    while i < msg.len() {
+        let c = msg[i];
+        if !c.is_ascii_digit() {
+            return Err(RejectReason::Unparseable);
+        }
+        result = match result.checked_mul(10) {
+            Some(x) => match x.checked_add((c - b'0') as u32) {
+                Some(x) => x,
+                None => {
+                    overflow = true;
+                    0
+                }
+            },
+            None => {
+                overflow = true;
+                0
+            }
+        };
+        i += 1;
+    }
+    if overflow {
+        return Err(RejectReason::OutOfRange);
+    }
+    Ok(result)
+}
+
+// Parses a JSON object with a single `state` or `percent` key. Only the subset of JSON that is
+// needed for this is supported: no escapes in strings, and no nested values. A second key ends up
+// in the value of the first one, which then does not parse.
+const fn parse_set_json(msg: &[u8]) -> Result<SetCommand, RejectReason> {
+    let object = match trim_whitespace(msg) {
+        [b'{', object @ .., b'}'] => object,
+        _ => return Err(RejectReason::Unparseable),
+    };
+    let mut colon = 0;
+    while colon < object.len() && object[colon] != b':' {
+        colon += 1;
+    }
+    let (key, value) = match object.split_at(colon) {
+        (key, [b':', value @ ..]) => (trim_whitespace(key), trim_whitespace(value)),
+        _ => return Err(RejectReason::Unparseable),
+    };
+    match key {
+        br#""state""# => match value {
+            [b'"', word @ .., b'"'] => parse_set_word(word),
+            _ => Err(RejectReason::Unparseable),
+        },
+        br#""percent""# => match parse_percent(value) {
+            Ok(target) => Ok(SetCommand::Set(target)),
+            Err(reason) => Err(reason),
+        },
+        _ => Err(RejectReason::Unparseable),
+    }
+}
+
+// The set topic accepts the documented forms, and rejects anything around them.
+const _: () = {
+    assert!(matches!(
+        parse_set_command(b"medium"),
+        Ok(SetCommand::Set(TargetState::On(PowerLevel::Medium)))
+    ));
+    assert!(matches!(
+        parse_set_command(b"50"),
+        Ok(SetCommand::Set(TargetState::On(PowerLevel::Medium)))
+    ));
+    assert!(matches!(
+        parse_set_command(b"0"),
+        Ok(SetCommand::Set(TargetState::Off))
+    ));
+    assert!(matches!(
+        parse_set_command(b"101"),
+        Err(RejectReason::OutOfRange)
+    ));
+    assert!(matches!(
+        parse_set_command(b"99999999999"),
+        Err(RejectReason::OutOfRange)
+    ));
+    assert!(matches!(
+        parse_set_command(b"boost 99999999999"),
+        Err(RejectReason::OutOfRange)
+    ));
+    assert!(matches!(
+        parse_set_command(b"boost 99999999999x"),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(b"boost 3601"),
+        Err(RejectReason::OutOfRange)
+    ));
+    assert!(matches!(
+        parse_set_command(b"boost 600"),
+        Ok(SetCommand::Boost(600))
+    ));
+    assert!(matches!(
+        parse_set_command(b"boost 0"),
+        Err(RejectReason::OutOfRange)
+    ));
+    assert!(matches!(
+        parse_set_command(b"50\n"),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(b"high\n"),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(b"{ \"state\" : \"low\" }\n"),
+        Ok(SetCommand::Set(TargetState::On(PowerLevel::Low)))
+    ));
+    assert!(matches!(
+        parse_set_command(br#"{"percent":67}"#),
+        Ok(SetCommand::Set(TargetState::On(PowerLevel::High)))
+    ));
+    assert!(matches!(
+        parse_set_command(br#"{"percent":101}"#),
+        Err(RejectReason::OutOfRange)
+    ));
+    assert!(matches!(
+        parse_set_command(br#"{"state":"boost 10"}"#),
+        Ok(SetCommand::Boost(10))
+    ));
+    assert!(matches!(
+        parse_set_command(br#"{"state":"low","percent":50}"#),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(br#"{"percent":50,"state":"low"}"#),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(br#" {"state":"low"}"#),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(br#"{"level":"low"}"#),
+        Err(RejectReason::Unparseable)
+    ));
+    assert!(matches!(
+        parse_set_command(b""),
+        Err(RejectReason::Unparseable)
+    ));
+};
+
+// Parses the arguments of the override command: `<state> <secs>`, where the state is formatted as
+// in the state topic.
+fn parse_override(args: &[u8]) -> Option<MqttCommand> {
+    let space = args.iter().position(|&c| c == b' ')?;
+    Some(MqttCommand::OverrideState(
+        state::DeviceState::from_bytes(&args[..space])?,
+        parse_decimal(&args[space + 1..]).ok()?,
+    ))
+}
+
+// Parses the arguments of the safety command: `maxon <secs>` or `deadman <secs>`.
+fn parse_safety(args: &[u8]) -> Option<MqttCommand> {
+    let (limit, secs) = match args {
+        [b'm', b'a', b'x', b'o', b'n', b' ', secs @ ..] => (state::SafetyLimit::MaxOn, secs),
+        [b'd', b'e', b'a', b'd', b'm', b'a', b'n', b' ', secs @ ..] => {
+            (state::SafetyLimit::Deadman, secs)
+        }
+        _ => return None,
+    };
+    Some(MqttCommand::SetSafetyLimit(
+        limit,
+        parse_decimal(secs).ok()?,
+    ))
+}
+
+const fn trim_whitespace(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    while let [rest @ .., last] = s {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+// Longest accepted debug mode.
+const MAX_DEBUG_SECS: u32 = 60 * 60;
+
 // Converts a raw incoming message into a parsed command.
 fn process_incoming(
     topic: &str,
     msg: &[u8],
     mqtt_topics: &crate::config::MqttTopics,
+    log_unknown_topics: bool,
 ) -> MqttCommand {
     if topic == mqtt_topics.set {
-        match msg {
-            b"off" => MqttCommand::Set(TargetState::Off),
-            b"low" => MqttCommand::Set(TargetState::On(PowerLevel::Low)),
-            b"medium" => MqttCommand::Set(TargetState::On(PowerLevel::Medium)),
-            b"high" => MqttCommand::Set(TargetState::On(PowerLevel::High)),
-            _ => {
-                mqtt_log!("Received unknown set command: {:?}", msg);
-                MqttCommand::Unknown
+        match parse_set_command(msg) {
+            Ok(command) => command.into_command(),
+            Err(reason) => {
+                mqtt_log!(
+                    "Received invalid set command ({}): {:?}",
+                    reason.as_str(),
+                    msg
+                );
+                MqttCommand::Rejected(reason)
             }
         }
     } else if topic == mqtt_topics.cmd {
@@ -293,27 +807,311 @@ fn process_incoming(
             [b'p', b'i', b'n', b'g', b' ', ping @ ..] => {
                 // TODO: Print as a string?
                 mqtt_log!("Pong: {:?}", ping);
-                MqttCommand::Unknown
+                MqttCommand::Ping
+            }
+            b"counts" => MqttCommand::PublishCommandCounts,
+            b"trace on" => MqttCommand::StartTrace,
+            b"trace off" => MqttCommand::StopTrace,
+            b"refresh" => MqttCommand::RefreshSafetyTimer,
+            b"resume" => MqttCommand::ResumeActuation,
+            b"laststates" => MqttCommand::PublishLastStates,
+            b"diag" => MqttCommand::PublishDiagnostics,
+            b"whatif" => MqttCommand::PublishWhatIf,
+            b"actuator" => MqttCommand::PublishActuatorStatus,
+            b"confidence" => MqttCommand::PublishConfidence,
+            b"announce" => MqttCommand::Reannounce,
+            b"reset counters" => MqttCommand::ResetCounters,
+            b"reload" => MqttCommand::ReloadFlashConfig,
+            // Guarded by the confirmation, so that a stray `factory reset` does not wipe a device.
+            b"factory reset confirm" => MqttCommand::FactoryReset,
+            b"factory reset" => {
+                mqtt_log!("Send `factory reset confirm` to erase the flash config and reboot");
+                MqttCommand::Rejected(RejectReason::Unparseable)
+            }
+            [b'b', b'l', b'i', b'n', b'k', b' ', ms @ ..] => match parse_decimal(ms) {
+                Ok(ms) => MqttCommand::SetBlinkDuration(ms),
+                Err(reason) => {
+                    mqtt_log!("Received invalid blink duration: {:?}", ms);
+                    MqttCommand::Rejected(reason)
+                }
+            },
+            [b'o', b'v', b'e', b'r', b'r', b'i', b'd', b'e', b' ', args @ ..] => {
+                match parse_override(args) {
+                    Some(command) => command,
+                    None => {
+                        mqtt_log!("Received invalid override command: {:?}", args);
+                        MqttCommand::Rejected(RejectReason::Unparseable)
+                    }
+                }
+            }
+            [b'd', b'e', b'b', b'u', b'g', b' ', secs @ ..] => match parse_decimal(secs) {
+                Ok(secs @ 1..=MAX_DEBUG_SECS) => MqttCommand::StartDebug(secs),
+                Ok(secs) => {
+                    mqtt_log!("Debug duration {}s is out of range", secs);
+                    MqttCommand::Rejected(RejectReason::OutOfRange)
+                }
+                Err(reason) => {
+                    mqtt_log!("Received invalid debug duration: {:?}", secs);
+                    MqttCommand::Rejected(reason)
+                }
+            },
+            [b's', b'a', b'f', b'e', b't', b'y', b' ', args @ ..] => match parse_safety(args) {
+                Some(command) => command,
+                None => {
+                    mqtt_log!("Received invalid safety command: {:?}", args);
+                    MqttCommand::Rejected(RejectReason::Unparseable)
+                }
+            },
+            [b'e', b'c', b'h', b'o', b' ', text @ ..] => {
+                let text = &text[..text.len().min(ECHO_CAPACITY)];
+                // Cannot fail: the text is truncated to the capacity above.
+                MqttCommand::Echo(Vec::from_slice(text).unwrap())
             }
             _ => {
                 mqtt_log!("Received unknown cmd command: {:?}", msg);
-                MqttCommand::Unknown
+                MqttCommand::Rejected(RejectReason::Unparseable)
             }
         }
     } else {
-        mqtt_log!("Received unknown topic: {}", topic);
-        MqttCommand::Unknown
+        // Messages on other topics may be expected, for example if the prefix is shared with other
+        // devices; by default, they are only counted.
+        if log_unknown_topics {
+            mqtt_log!("Received unknown topic: {}", topic);
+        }
+        MqttCommand::Rejected(RejectReason::UnknownTopic)
     }
 }
 
+// Time between a factory reset and the reboot, for the log to reach the broker.
+const FACTORY_RESET_REBOOT_DELAY: Duration = Duration::from_secs(2);
+
+// Maximal size of a publication with batched log messages. Fits several messages of the maximal
+// length.
+const LOG_BATCH_CAPACITY: usize = 1024;
+
 const STATE_UPDATE_PERIOD: Duration = Duration::from_secs(60);
+const ANNOUNCEMENT_PERIOD: Duration = Duration::from_secs(600);
+const CLOCK_UPDATE_PERIOD: Duration = Duration::from_secs(60);
+
+// Time after which an MQTT connection is considered stable, so that the next broker-initiated
+// disconnect is retried with the minimal delay again.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+// For how long at most the observed state is held back after an optimistic state was published.
+// Covers the button pushes and recognising the new state.
+const OPTIMISTIC_HOLD: Duration = Duration::from_secs(30);
+
+// Maximal random delay before the first attempt to connect to the broker.
+const INITIAL_CONNECT_JITTER: Duration = Duration::from_secs(2);
+
+// Number of consecutive connection failures after which the broker host name is resolved again.
+const RESOLVE_AGAIN_AFTER_FAILURES: u32 = 5;
+
+// Returns a random duration up to `max`, so that a fleet of devices that lost the connection at
+// the same time (for example, after an access point reboot) does not reconnect all at once.
+fn jitter(max: Duration) -> Duration {
+    Duration::from_ticks(RoscRng.next_u64() % (max.as_ticks() + 1))
+}
+
+// Exponentially growing delay between failed attempts to connect to the broker, with up to a
+// quarter of the delay added randomly.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const MIN_DELAY: Duration = Duration::from_secs(1);
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    fn new() -> Backoff {
+        Backoff {
+            delay: Self::MIN_DELAY,
+        }
+    }
+
+    // Called after a successful connection.
+    fn reset(&mut self) {
+        self.delay = Self::MIN_DELAY;
+    }
+
+    // Called after a failed connection attempt.
+    async fn wait(&mut self) {
+        let delay = self.delay + jitter(self.delay / 4);
+        log::info!("Retrying connection in {}ms", delay.as_millis());
+        Timer::after(delay).await;
+        self.delay = (self.delay * 2).min(Self::MAX_DELAY);
+    }
+}
+
+// The will that makes the broker publish `offline` to the availability topic when the connection
+// is lost without a disconnect. Sent with the same QoS and retain flag as `online`, so that
+// subscribers see both consistently.
+fn availability_will(
+    topics: &crate::config::MqttTopics,
+    options: &crate::config::MqttOptions,
+) -> minimq::Will<'static> {
+    let mut will = minimq::Will::new(topics.availability, b"offline", &[])
+        .unwrap()
+        .qos(availability_qos(options));
+    if options.availability_retain {
+        will = will.retained();
+    }
+    will
+}
+
+// The `online` publication to the availability topic, matching availability_will(), to be finished
+// by the caller.
+fn availability_online(
+    topics: &crate::config::MqttTopics,
+    options: &crate::config::MqttOptions,
+) -> Publication<'static, &'static [u8]> {
+    let mut publication = Publication::new(&b"online"[..])
+        .topic(topics.availability)
+        .qos(availability_qos(options));
+    if options.availability_retain {
+        publication = publication.retain();
+    }
+    publication
+}
+
+fn availability_qos(options: &crate::config::MqttOptions) -> QoS {
+    if options.availability_qos1 {
+        QoS::AtLeastOnce
+    } else {
+        QoS::AtMostOnce
+    }
+}
+
+// Mirrors the state and availability to the second broker. Best-effort: nothing is retried beyond
+// the periodic republishing, and nothing is received. The buffers are much smaller than the main
+// connection's, as only short publications are sent.
+#[cfg(feature = "bridge")]
+#[embassy_executor::task]
+pub(super) async fn bridge_task(
+    network_stack: &'static embassy_net::Stack<cyw43::NetDriver<'static>>,
+    config: &'static crate::config::Config,
+) {
+    // Same as in minimq_task().
+    #![allow(clippy::await_holding_refcell_ref)]
+
+    let topics = &config.mqtt_topics;
+    let options = &config.mqtt_options;
+    let (emb_endpoint, enal_endpoint) = interop::parse_endpoint(config.bridge_endpoint);
+    let enal_endpoint = Cell::new(enal_endpoint);
+
+    let mut socket_rx_buffer = [0; 1024];
+    let mut socket_tx_buffer = [0; 1024];
+    let socket = RefCell::new(TcpSocket::new(
+        network_stack,
+        &mut socket_rx_buffer,
+        &mut socket_tx_buffer,
+    ));
+    let blocking_stack =
+        interop::BlockingSocketStack::new(&socket, &enal_endpoint, options.reset_grace_checks);
+
+    let mut minimq_buffer = [0; 2048];
+    let mut minimq_config =
+        minimq::ConfigBuilder::new(interop::SharedBroker(&enal_endpoint), &mut minimq_buffer)
+            .client_id(config.mqtt_client_id)
+            .unwrap()
+            .will(availability_will(topics, options))
+            .unwrap();
+    if let Some(auth) = &config.bridge_auth {
+        minimq_config = minimq_config
+            .set_auth(auth.username, auth.password)
+            .unwrap();
+    }
+    let mut minimq = minimq::Minimq::new(blocking_stack, interop::Clock, minimq_config);
+
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let mut backoff = Backoff::new();
+    let mut need_publish_availability = true;
+    // None if the state needs to be published right away.
+    let mut last_published_state: Option<(Instant, state::DeviceState)> = None;
+    let mut mqtt_not_connected_since: Option<Instant> = None;
+    loop {
+        ticker.next().await;
+
+        if interop::ensure_connected(
+            socket.borrow_mut().deref_mut(),
+            &emb_endpoint,
+            options.connect_timeout,
+        )
+        .await
+        {
+            backoff.reset();
+        } else {
+            mqtt_not_connected_since = None;
+            backoff.wait().await;
+            continue;
+        }
+
+        match minimq.poll(|_, _, _, _| ()) {
+            Ok(_) => {}
+            Err(minimq::Error::SessionReset) => {
+                log::info!("Bridge MQTT connection was reset");
+                need_publish_availability = true;
+                last_published_state = None;
+            }
+            Err(err) => log::warn!("Error from bridge minimq::poll(): {:?}", err),
+        }
+
+        let now = Instant::now();
+        if !minimq.client().is_connected() {
+            let since = *mqtt_not_connected_since.get_or_insert(now);
+            if now.duration_since(since) > options.handshake_timeout {
+                log::warn!("Bridge MQTT connection was not established; reconnecting");
+                socket.borrow_mut().abort();
+                mqtt_not_connected_since = None;
+            }
+            continue;
+        }
+        mqtt_not_connected_since = None;
+
+        if need_publish_availability {
+            match minimq
+                .client()
+                .publish(availability_online(topics, options).finish().unwrap())
+            {
+                Ok(()) => need_publish_availability = false,
+                Err(err) => log::warn!("Error publishing bridge availability: {:?}", err),
+            }
+        }
+
+        // The same state as on the main broker, from the same accessor.
+        let new_state = state::get_current_state(now);
+        let due = match last_published_state {
+            None => true,
+            Some((at, state)) => {
+                now.duration_since(at) > STATE_UPDATE_PERIOD
+                    || (state != new_state && new_state != state::DeviceState::Unknown)
+            }
+        };
+        if state::has_valid_reading(now) && due {
+            match minimq.client().publish(
+                Publication::new(new_state.as_bytes())
+                    .topic(topics.state)
+                    .retain()
+                    .finish()
+                    .unwrap(),
+            ) {
+                Ok(()) => last_published_state = Some((now, new_state)),
+                Err(err) => log::warn!("Error publishing bridge state: {:?}", err),
+            }
+        }
+    }
+}
 
 #[embassy_executor::task]
 pub(super) async fn minimq_task(
     network_stack: &'static embassy_net::Stack<cyw43::NetDriver<'static>>,
-    topics: &'static crate::config::MqttTopics,
-    endpoint: ((u8, u8, u8, u8), u16),
-    log_receiver: Receiver<'static, ThreadModeRawMutex, String<256>, 16>,
+    wifi_control: &'static SharedControl,
+    config: &'static crate::config::Config,
+    // config.mqtt_endpoint, with the host name resolved. Resolved again after repeated connection
+    // failures, in case the broker has moved.
+    broker_endpoint: ((u8, u8, u8, u8), u16),
+    log_receiver: Receiver<'static, CriticalSectionRawMutex, String<256>, 16>,
+    alert_receiver: Receiver<'static, CriticalSectionRawMutex, &'static str, 8>,
 ) {
     // This warning triggers for the ensure_connected() call, but for some reason I couldn't attach
     // the annotation to the statement where the warning is happening.
@@ -322,7 +1120,15 @@ pub(super) async fn minimq_task(
     // TODO: Find a way to attach the annotation to the statement.
     #![allow(clippy::await_holding_refcell_ref)]
 
-    let (emb_endpoint, enal_endpoint, minimq_endpoint) = interop::parse_endpoint(endpoint);
+    let topics = &config.mqtt_topics;
+    let options = &config.mqtt_options;
+    let (mut emb_endpoint, enal_endpoint) = interop::parse_endpoint(broker_endpoint);
+    let enal_endpoint = Cell::new(enal_endpoint);
+    if !options.availability_retain {
+        mqtt_log!(
+            "Availability is not retained, so late subscribers cannot tell if the device is up"
+        );
+    }
 
     let mut socket_rx_buffer = [0; 4096];
     let mut socket_tx_buffer = [0; 4096];
@@ -335,25 +1141,189 @@ pub(super) async fn minimq_task(
         &mut socket_tx_buffer,
     ));
 
-    let blocking_stack = interop::BlockingSocketStack::new(&socket, enal_endpoint);
+    let blocking_stack =
+        interop::BlockingSocketStack::new(&socket, &enal_endpoint, options.reset_grace_checks);
 
     let mut minimq_buffer = [0; 8192];
-    let mut minimq = minimq::Minimq::new(
-        blocking_stack,
-        interop::Clock,
-        minimq::ConfigBuilder::new(minimq_endpoint, &mut minimq_buffer)
-            .client_id("f58mqtt")
-            .unwrap(),
-    );
+    let mut minimq_config =
+        minimq::ConfigBuilder::new(interop::SharedBroker(&enal_endpoint), &mut minimq_buffer)
+            .client_id(config.mqtt_client_id)
+            .unwrap()
+            .session_expiry_interval(options.session_expiry_secs)
+            // The broker publishes `offline` when the connection is lost without a disconnect.
+            .will(availability_will(topics, options))
+            .unwrap();
+    if let Some(auth) = &config.mqtt_auth {
+        minimq_config = minimq_config
+            .set_auth(auth.username, auth.password)
+            .unwrap();
+    }
+    let mut minimq = minimq::Minimq::new(blocking_stack, interop::Clock, minimq_config);
 
     let mut last_published_state = (Instant::now(), state::DeviceState::Unknown);
+    // Whether the state was published since boot. The first state is published as soon as the
+    // detector has a valid reading, even if it is unknown.
+    let mut boot_snapshot_published = false;
+    let mut last_published_target: Option<TargetState> = None;
+    // Time and value of the last commanded target state that the device has not reached yet.
+    let mut pending_target: Option<(Instant, TargetState)> = None;
+    // Until when the observed state is held back after an optimistic state was published, unless it
+    // reaches the target state.
+    let mut optimistic: Option<(Instant, TargetState)> = None;
+    let mut command_counts = CommandCounts::default();
+    // Log message that failed to publish, to be retried before the ones remaining in the channel.
+    let mut pending_log: Option<String<256>> = None;
+    // Log messages joined for publishing in a single publication, if batching is enabled. Kept
+    // until it is published.
+    let mut log_batch = String::<LOG_BATCH_CAPACITY>::new();
+    // Time and value of the oldest QoS1 state publication not yet acknowledged by the broker.
+    let mut unacked_state: Option<(Instant, state::DeviceState)> = None;
+    let state_qos = if options.state_qos1 {
+        QoS::AtLeastOnce
+    } else {
+        QoS::AtMostOnce
+    };
 
     let mut ticker = Ticker::every(Duration::from_secs(1));
     let mut need_resubscribe = true;
+    // Until when the next set message is taken for the retained one, delivered by the broker right
+    // after subscribing. None once it arrived, or if it should be applied.
+    let mut ignore_set_until: Option<Instant> = None;
+    // The firmware version is retained, so it only needs to be published once per session.
+    let mut need_publish_fw_hash = true;
+    let mut need_publish_info = true;
+    let mut need_publish_selfcheck = config.selfcheck;
+    // Set once the boot is done, which is on the first connection.
+    let mut need_publish_boot = false;
+    let mut need_publish_broker = true;
+    let mut need_publish_location = !config.location.is_empty();
+    // Overrides the retained `offline` that the broker publishes if the previous connection was
+    // lost.
+    let mut need_publish_availability = true;
+    // Whether the device was reported as subscribed to the command topics. None if nothing was
+    // published yet.
+    let mut last_published_subscribed: Option<bool> = None;
+    let mut session_info = SessionInfo {
+        connections: 0,
+        session_present: false,
+        pending_messages: false,
+        subscriptions_pending: false,
+    };
+    let mut last_published_session_info: Option<SessionInfo> = None;
+    let mut was_connected = false;
+    // Until when the debug mode lasts. It raises the USB log level (which includes the minimq and
+    // socket logs), traces the raw LED changes, and publishes the session state.
+    let mut debug_until: Option<Instant> = None;
+    // When to reboot after a factory reset; delayed so that the log reaches the broker.
+    let mut reboot_at: Option<Instant> = None;
+    // When the announcement was last published. None if it needs to be published right away.
+    let mut last_announcement: Option<Instant> = None;
+    // When the clock was last published. None if it needs to be published right away.
+    let mut last_clock_update: Option<Instant> = None;
+    // When the signal strength was last published. None if it needs to be published right away.
+    let mut last_rssi_update: Option<Instant> = None;
+    // The flash write counts as last published. None if they need to be published right away.
+    let mut last_published_flash_writes: Option<(u32, u32)> = None;
+    // The state override as last published. None if nothing was published yet, so that a stale
+    // retained override is cleared after a reboot.
+    let mut last_published_override: Option<Option<state::DeviceState>> = None;
+    let mut backoff = Backoff::new();
+    // The TCP connection usually succeeds after the broker disconnects the client, so the backoff
+    // after a disconnect is separate, and is reset only once the connection has been stable.
+    let mut broker_disconnect_backoff = Backoff::new();
+    // Since when the MQTT connection is established.
+    let mut connected_since: Option<Instant> = None;
+    // Since when the TCP connection is established, but the MQTT connection is not.
+    let mut mqtt_not_connected_since: Option<Instant> = None;
+    let mut last_address = network_stack.config_v4().map(|config| config.address);
+    Timer::after(jitter(INITIAL_CONNECT_JITTER)).await;
+    // Diagnostics for the diag command.
+    let mut connection_failures = 0u32;
+    // Connection failures since the last successful connection, or since the broker host name was
+    // last resolved.
+    let mut consecutive_connection_failures = 0u32;
+    let mut session_resets = 0u32;
+    let mut broker_disconnects = 0u32;
+    // Time the last and the slowest complete iterations of the loop took.
+    let mut last_loop_time = Duration::from_ticks(0);
+    let mut max_loop_time = Duration::from_ticks(0);
     loop {
-        interop::ensure_connected(socket.borrow_mut().deref_mut(), &emb_endpoint).await;
+        let loop_start = Instant::now();
 
-        match minimq.poll(|_, topic, msg, _| process_incoming(topic, msg, topics)) {
+        // embassy_net renews the DHCP lease in the background, but the socket stays bound to the
+        // old address if the address changes, so it has to be reopened.
+        let address = network_stack.config_v4().map(|config| config.address);
+        if address != last_address {
+            mqtt_log!(
+                "Address changed from {:?} to {:?}; reconnecting",
+                last_address,
+                address
+            );
+            socket.borrow_mut().abort();
+            last_address = address;
+        }
+        if address.is_none() {
+            // Lost the DHCP lease; there is no point in connecting until a new one is received.
+            ticker.next().await;
+            continue;
+        }
+
+        if interop::ensure_connected(
+            socket.borrow_mut().deref_mut(),
+            &emb_endpoint,
+            options.connect_timeout,
+        )
+        .await
+        {
+            backoff.reset();
+            consecutive_connection_failures = 0;
+        } else {
+            connection_failures += 1;
+            consecutive_connection_failures += 1;
+            mqtt_not_connected_since = None;
+            status_led::set_mqtt_connected(false);
+            if consecutive_connection_failures >= RESOLVE_AGAIN_AFTER_FAILURES {
+                consecutive_connection_failures = 0;
+                if let Some(endpoint) =
+                    init_network::resolve_broker_again(network_stack, config.mqtt_endpoint).await
+                {
+                    let (new_emb_endpoint, new_enal_endpoint) = interop::parse_endpoint(endpoint);
+                    if new_enal_endpoint != enal_endpoint.get() {
+                        mqtt_log!(
+                            "The broker has moved from {} to {}",
+                            enal_endpoint.get(),
+                            new_enal_endpoint
+                        );
+                        emb_endpoint = new_emb_endpoint;
+                        enal_endpoint.set(new_enal_endpoint);
+                        need_publish_broker = true;
+                    }
+                }
+            }
+            backoff.wait().await;
+            continue;
+        }
+
+        let command = minimq.poll(|_, topic, msg, _| {
+            if topic == topics.set
+                && ignore_set_until
+                    .take()
+                    .is_some_and(|until| Instant::now() < until)
+            {
+                mqtt_log!(
+                    "Ignoring set command received right after subscribing: {:?}",
+                    msg
+                );
+                return MqttCommand::IgnoredRetainedSet;
+            }
+            process_incoming(topic, msg, topics, options.log_unknown_topics)
+        });
+        if let Ok(Some(command)) = &command {
+            command_counts.record(command);
+        }
+        // minimq reports the lost session when processing the CONNACK.
+        let session_reset = matches!(command, Err(minimq::Error::SessionReset));
+        match command {
             Ok(None) => {
                 // No command.
             }
@@ -361,13 +1331,400 @@ pub(super) async fn minimq_task(
                 // Received a command.
                 log::info!("Received a command: Set({:?})", state);
                 state::set_target_state(state).await;
+                pending_target = Some((Instant::now(), state));
+                if options.optimistic_state
+                    && !state.is_reached(state::get_current_state(Instant::now()))
+                {
+                    let expected = state.expected_state();
+                    // Marked on the change topic, as the state topic only carries the state.
+                    let mut s = String::<64>::new();
+                    // The longest possible output is 48 bytes, so it always fits.
+                    write!(
+                        s,
+                        r#"{{"state":"{}","source":"optimistic"}}"#,
+                        expected.as_str()
+                    )
+                    .unwrap();
+                    let published = minimq
+                        .client()
+                        .publish(
+                            Publication::new(s.as_bytes())
+                                .topic(topics.change)
+                                .finish()
+                                .unwrap(),
+                        )
+                        .and_then(|()| {
+                            minimq.client().publish(
+                                Publication::new(expected.as_bytes())
+                                    .topic(topics.state)
+                                    .qos(state_qos)
+                                    .retain()
+                                    .finish()
+                                    .unwrap(),
+                            )
+                        });
+                    match published {
+                        Ok(()) => optimistic = Some((Instant::now() + OPTIMISTIC_HOLD, state)),
+                        Err(err) => log::info!("Error publishing optimistic state: {:?}", err),
+                    }
+                }
+            }
+            Ok(Some(MqttCommand::StepUp)) => {
+                let state = state::step_target_state(true).await;
+                log::info!("Received a command: StepUp; new target: {:?}", state);
+                pending_target = Some((Instant::now(), state));
+            }
+            Ok(Some(MqttCommand::StepDown)) => {
+                let state = state::step_target_state(false).await;
+                log::info!("Received a command: StepDown; new target: {:?}", state);
+                pending_target = Some((Instant::now(), state));
+            }
+            Ok(Some(MqttCommand::Boost(secs))) => {
+                let restored = state::start_boost(Duration::from_secs(secs.into())).await;
+                mqtt_log!(
+                    "Boosting to high for {}s, then restoring target {}",
+                    secs,
+                    restored.as_str()
+                );
+                pending_target = Some((Instant::now(), TargetState::On(PowerLevel::High)));
             }
-            Ok(Some(MqttCommand::Unknown)) => {
-                // Unknown command was already logged in the process_incoming() implementation.
+            Ok(Some(MqttCommand::IgnoredRetainedSet)) => {
+                // Already logged in the poll() handler.
+            }
+            Ok(Some(MqttCommand::Ping)) => {
+                // Ping was already answered in the process_incoming() implementation.
+            }
+            Ok(Some(MqttCommand::Rejected(RejectReason::UnknownTopic)))
+                if !options.log_unknown_topics =>
+            {
+                // Only counted.
+            }
+            Ok(Some(MqttCommand::Rejected(reason))) => {
+                // The message was already logged in the process_incoming() implementation; tell
+                // the sender why it was rejected.
+                match minimq.client().publish(
+                    Publication::new(reason.as_str().as_bytes())
+                        .topic(topics.error)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing rejection reason: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::PublishCommandCounts)) => {
+                match minimq.client().publish(
+                    Publication::new(command_counts.to_json().as_bytes())
+                        .topic(topics.cmd_counts)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing command counts: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::Echo(text))) => {
+                match minimq.client().publish(
+                    Publication::new(text.as_slice())
+                        .topic(topics.echo)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing echo: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::StartTrace)) => {
+                let duration = state::start_trace(Instant::now());
+                mqtt_log!("Tracing LED changes for {}s", duration.as_secs());
+            }
+            Ok(Some(MqttCommand::StopTrace)) => {
+                state::stop_trace();
+                mqtt_log!("Stopped tracing LED changes");
+            }
+            Ok(Some(MqttCommand::RefreshSafetyTimer)) => {
+                state::refresh_safety_timer();
+                log::info!("Received a command: RefreshSafetyTimer");
+            }
+            Ok(Some(MqttCommand::ResumeActuation)) => {
+                state::resume_actuation();
+                log::info!("Received a command: ResumeActuation");
+            }
+            Ok(Some(MqttCommand::SetBlinkDuration(ms))) => {
+                match state::set_blink_duration(Duration::from_millis(ms.into())) {
+                    Ok(()) => mqtt_log!("Blink duration set to {}ms", ms),
+                    Err(err) => mqtt_log!("Cannot set blink duration to {}ms: {}", ms, err),
+                }
+            }
+            Ok(Some(MqttCommand::OverrideState(state, secs))) => {
+                if options.allow_state_override {
+                    let duration = Duration::from_secs(secs.into());
+                    state::set_state_override(state, Instant::now() + duration);
+                    mqtt_log!(
+                        "Overriding the observed state with {} for {}s",
+                        state.as_str(),
+                        secs
+                    );
+                } else {
+                    mqtt_log!("State override is disabled; set $F58_STATE_OVERRIDE to enable it");
+                }
+            }
+            Ok(Some(MqttCommand::SetSafetyLimit(limit, secs))) => {
+                match state::set_safety_limit(limit, secs.into()) {
+                    Ok(()) => mqtt_log!("Safety limit {} set to {}s", limit.as_str(), secs),
+                    Err(err) => mqtt_log!(
+                        "Cannot set safety limit {} to {}s: {}",
+                        limit.as_str(),
+                        secs,
+                        err
+                    ),
+                }
+            }
+            Ok(Some(MqttCommand::StartDebug(secs))) => {
+                let until = Instant::now() + Duration::from_secs(secs.into());
+                debug_until = Some(until);
+                log::set_max_level(log::LevelFilter::Trace);
+                state::start_trace_until(until);
+                mqtt_log!("Debug mode started for {}s", secs);
+            }
+            Ok(Some(MqttCommand::ReloadFlashConfig)) => match crate::flash_config::reload() {
+                Ok(()) => mqtt_log!(
+                    "Reloaded the safety limits from the flash config; the other fields take \
+                     effect after a reboot"
+                ),
+                Err(err) => mqtt_log!("Cannot reload the flash config: {}", err),
+            },
+            Ok(Some(MqttCommand::FactoryReset)) => {
+                match crate::flash_config::erase() {
+                    Ok(()) => mqtt_log!("Factory reset: erased the flash config; rebooting"),
+                    // A reboot still brings back every setting adjusted at runtime.
+                    Err(err) => mqtt_log!("Factory reset: {}; rebooting", err),
+                }
+                reboot_at = Some(Instant::now() + FACTORY_RESET_REBOOT_DELAY);
+            }
+            Ok(Some(MqttCommand::PublishLastStates)) => {
+                // JSON array of `{"t":<uptime ms>,"state":"<state>"}`, from the oldest to the
+                // newest.
+                let mut s = String::<512>::new();
+                let mut first = true;
+                s.push('[').unwrap();
+                for (at, state) in state::get_state_history() {
+                    // Each entry is at most 52 bytes, so all of them always fit.
+                    write!(
+                        s,
+                        r#"{}{{"t":{},"state":"{}"}}"#,
+                        if first { "" } else { "," },
+                        at.as_millis(),
+                        state.as_str()
+                    )
+                    .unwrap();
+                    first = false;
+                }
+                s.push(']').unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.last_states)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing last states: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::PublishDiagnostics)) => {
+                // Like the RSSI topic, the control is not waited for if another task holds it;
+                // the signal strength is null then. Free memory is not reported: there is no heap,
+                // so it is fixed at link time, and measuring the stack headroom would require
+                // painting the stack at startup.
+                let rssi = match wifi_control.try_lock() {
+                    Ok(mut control) => Some(control.get_rssi().await),
+                    Err(_) => None,
+                };
+                let mut rssi_json = String::<12>::new();
+                match rssi {
+                    // The longest possible output is 11 bytes, so it always fits.
+                    Some(rssi) => write!(rssi_json, "{}", rssi).unwrap(),
+                    None => rssi_json.push_str("null").unwrap(),
+                }
+                let mut s = String::<320>::new();
+                // The longest possible output is 307 bytes, so it always fits.
+                write!(
+                    s,
+                    concat!(
+                        r#"{{"uptime_s":{},"loop_ms":{{"last":{},"max":{}}},"#,
+                        r#""connection_failures":{},"session_resets":{},"broker_disconnects":{},"#,
+                        r#""rssi":{},"commands":{}}}"#
+                    ),
+                    Instant::now().as_secs(),
+                    last_loop_time.as_millis(),
+                    max_loop_time.as_millis(),
+                    connection_failures,
+                    session_resets,
+                    broker_disconnects,
+                    rssi_json,
+                    command_counts.to_json()
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.diag_all)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing diagnostics: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::ResetCounters)) => {
+                command_counts = CommandCounts::default();
+                connection_failures = 0;
+                session_resets = 0;
+                broker_disconnects = 0;
+                max_loop_time = Duration::from_ticks(0);
+                mqtt_log!(
+                    "Reset counters: command counts, connection failures, session resets, broker \
+                     disconnects, and the maximal loop time"
+                );
+            }
+            Ok(Some(MqttCommand::PublishWhatIf)) => {
+                let (current_state, target_state, plan) = state::what_if(Instant::now()).await;
+                let mut s = String::<160>::new();
+                // The longest possible output is 149 bytes, so it always fits.
+                write!(
+                    s,
+                    concat!(
+                        r#"{{"state":"{}","target":"{}","effective_target":"{}","action":"{}","#,
+                        r#""suppressed":"{}","safety_off":"{}"}}"#
+                    ),
+                    current_state.as_str(),
+                    target_state.as_str(),
+                    plan.target.as_str(),
+                    plan.action.as_str(),
+                    plan.suppressed.map_or("none", |reason| reason.as_str()),
+                    plan.safety_off.map_or("none", |limit| limit.as_str())
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.what_if)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing what-if: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::PublishActuatorStatus)) => {
+                let status = state::actuator_status();
+                let now = Instant::now();
+                let secs_since = |at: Option<Instant>| {
+                    at.and_then(|at| now.checked_duration_since(at))
+                        .map_or(0, |d| d.as_secs())
+                };
+                let secs_until = |until: Option<Instant>| {
+                    until
+                        .and_then(|until| until.checked_duration_since(now))
+                        .map_or(0, |d| d.as_secs())
+                };
+                let mut s = String::<256>::new();
+                // The longest possible output is 241 bytes, so it always fits.
+                write!(
+                    s,
+                    concat!(
+                        r#"{{"target":"{}","last_push":"{}","last_push_ago_s":{},"#,
+                        r#""unknown_state_s":{},"inhibit_s":{},"rate_limit_s":{},"cycles":{},"#,
+                        r#""given_up":"{}"}}"#
+                    ),
+                    status.target.as_str(),
+                    status
+                        .last_push
+                        .map_or("none", |(action, _)| action.as_str()),
+                    secs_since(status.last_push.map(|(_, at)| at)),
+                    secs_since(status.unknown_state_since),
+                    secs_until(status.inhibit_until),
+                    secs_until(status.rate_limited_until),
+                    status.actuation_cycles,
+                    status.given_up.unwrap_or("none")
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.actuator)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing actuator status: {:?}", err),
+                }
+            }
+            Ok(Some(MqttCommand::Reannounce)) => {
+                // Published in the same loop iteration, below.
+                need_publish_availability = true;
+                need_publish_info = true;
+                last_announcement = None;
+            }
+            Ok(Some(MqttCommand::PublishConfidence)) => {
+                let (margins, blink_duration) = state::blink_margins(Instant::now());
+                let mut s = String::<96>::new();
+                // The margins are capped by the blink duration, which is at most 5000ms, so the
+                // longest possible output is 60 bytes, and it always fits.
+                write!(
+                    s,
+                    r#"{{"blink_ms":{},"margin_ms":[{},{},{}],"min_ms":{}}}"#,
+                    blink_duration.as_millis(),
+                    margins[0].as_millis(),
+                    margins[1].as_millis(),
+                    margins[2].as_millis(),
+                    margins.iter().min().unwrap().as_millis()
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.confidence)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing confidence: {:?}", err),
+                }
             }
             Err(minimq::Error::SessionReset) => {
+                // With a persistent session, the broker keeps the subscriptions across reconnects,
+                // and minimq reports SessionReset only if the broker has lost the session. So the
+                // resubscribe happens only when it is really needed.
                 mqtt_log!("MQTT connection was reset!");
+                session_resets += 1;
+                // Warm reconnect: in addition to resubscribing, reassert everything the broker may
+                // have stale or lost in the same loop iteration, rather than waiting for the
+                // periodic updates.
                 need_resubscribe = true;
+                need_publish_availability = true;
+                last_published_target = None;
+                // Forgetting the state also forces a republish when only changes are published.
+                // Otherwise, the last published state is kept across reconnects, and the state is
+                // only republished once it changes or the periodic update is due.
+                if options.state_reassert {
+                    last_published_state = (Instant::MIN, state::DeviceState::Unknown);
+                }
+                need_publish_fw_hash = true;
+                need_publish_info = true;
+                need_publish_broker = true;
+                need_publish_location = !config.location.is_empty();
+                last_published_flash_writes = None;
+                last_announcement = None;
+            }
+            Err(err)
+                if was_connected
+                    && !minimq.client().is_connected()
+                    && !matches!(err, minimq::Error::Network(_)) =>
+            {
+                // The broker ended the MQTT connection (with a DISCONNECT packet) rather than the
+                // network failing. It may be refusing the client on purpose, so back off rather
+                // than reconnecting right away. The message is published once reconnected.
+                broker_disconnects += 1;
+                mqtt_log!("Disconnected by the broker: {:?}", err);
+                socket.borrow_mut().abort();
+                broker_disconnect_backoff.wait().await;
             }
             Err(err) => {
                 // Not logging to MQTT to avoid cascading growth of publications if the poll() error
@@ -376,52 +1733,646 @@ pub(super) async fn minimq_task(
             }
         }
 
+        if reboot_at.is_some_and(|at| Instant::now() >= at) {
+            log::warn!("rebooting after the factory reset...");
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        if debug_until.is_some_and(|until| Instant::now() >= until) {
+            // The trace mode expires by itself at the same time.
+            debug_until = None;
+            log::set_max_level(crate::LOG_LEVEL);
+            mqtt_log!("Debug mode ended");
+        }
+
+        // If the broker accepts the TCP connection but never completes the MQTT handshake (for
+        // example, the CONNACK never arrives), minimq would wait forever. Reconnect from scratch
+        // instead.
+        status_led::set_mqtt_connected(minimq.client().is_connected());
+        if minimq.client().is_connected() && !was_connected {
+            if boot::current_phase().is_some() {
+                boot::enter(BootPhase::Done);
+                need_publish_boot = true;
+            }
+            session_info.connections += 1;
+            session_info.session_present = !session_reset;
+            connected_since = Some(Instant::now());
+        }
+        was_connected = minimq.client().is_connected();
+        if minimq.client().is_connected() {
+            mqtt_not_connected_since = None;
+        } else {
+            let now = Instant::now();
+            let since = *mqtt_not_connected_since.get_or_insert(now);
+            if now.duration_since(since) > options.handshake_timeout {
+                mqtt_log!(
+                    "MQTT connection was not established in {}s; reconnecting",
+                    options.handshake_timeout.as_secs()
+                );
+                socket.borrow_mut().abort();
+                mqtt_not_connected_since = None;
+            }
+        }
+
         // minimq ignores publish() calls if it is not connected to the broker 🤦‍♀️. So trying to
         // publish while not connected does not make sense.
         if minimq.client().is_connected() {
+            state::record_broker_contact(Instant::now());
+            if connected_since.is_some_and(|since| since.elapsed() > STABLE_CONNECTION) {
+                broker_disconnect_backoff.reset();
+            }
+
             if need_resubscribe {
+                match minimq.client().subscribe(
+                    &[
+                        topics.set_subscription.into(),
+                        topics.cmd_subscription.into(),
+                    ],
+                    &[],
+                ) {
+                    Ok(()) => {
+                        need_resubscribe = false;
+                        if options.ignore_retained_set.as_ticks() != 0 {
+                            ignore_set_until = Some(Instant::now() + options.ignore_retained_set);
+                        }
+                    }
+                    Err(err) => log::warn!("Error subscribing to topics: {:?}", err),
+                }
+            }
+
+            // Being connected is not enough to receive commands: report whether the subscription
+            // has been made and acknowledged by the broker.
+            let subscribed = !need_resubscribe && !minimq.client().subscriptions_pending();
+            if last_published_subscribed != Some(subscribed) {
+                match minimq.client().publish(
+                    Publication::new(if subscribed {
+                        &br#"{"subscribed":true}"#[..]
+                    } else {
+                        &br#"{"subscribed":false}"#[..]
+                    })
+                    .topic(topics.mqtt_status)
+                    .retain()
+                    .finish()
+                    .unwrap(),
+                ) {
+                    Ok(()) => last_published_subscribed = Some(subscribed),
+                    Err(err) => log::warn!("Error publishing MQTT status: {:?}", err),
+                }
+            }
+
+            if options.debug_session || debug_until.is_some() {
+                session_info.pending_messages = minimq.client().pending_messages();
+                session_info.subscriptions_pending = minimq.client().subscriptions_pending();
+                if last_published_session_info != Some(session_info) {
+                    let mut s = String::<128>::new();
+                    // The longest possible output is 105 bytes, so it always fits.
+                    write!(
+                        s,
+                        concat!(
+                            r#"{{"connections":{},"session_present":{},"#,
+                            r#""pending_messages":{},"subscriptions_pending":{}}}"#
+                        ),
+                        session_info.connections,
+                        session_info.session_present,
+                        session_info.pending_messages,
+                        session_info.subscriptions_pending
+                    )
+                    .unwrap();
+                    match minimq.client().publish(
+                        Publication::new(s.as_bytes())
+                            .topic(topics.session)
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => last_published_session_info = Some(session_info),
+                        Err(err) => log::warn!("Error publishing session state: {:?}", err),
+                    }
+                }
+            }
+
+            if need_publish_availability {
                 match minimq
                     .client()
-                    .subscribe(&[topics.set.into(), topics.cmd.into()], &[])
+                    .publish(availability_online(topics, options).finish().unwrap())
                 {
-                    Ok(()) => need_resubscribe = false,
-                    Err(err) => log::warn!("Error subscribing to topics: {:?}", err),
+                    Ok(()) => need_publish_availability = false,
+                    Err(err) => log::warn!("Error publishing availability: {:?}", err),
                 }
             }
 
-            // Drain the logs channel and publish everything.
-            while let Ok(log_message) = log_receiver.try_receive() {
+            if need_publish_fw_hash {
                 match minimq.client().publish(
-                    Publication::new(log_message.as_bytes())
-                        .topic(topics.log)
+                    Publication::new(config.firmware_version.as_bytes())
+                        .topic(topics.fw_hash)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => need_publish_fw_hash = false,
+                    Err(err) => log::warn!("Error publishing firmware hash: {:?}", err),
+                }
+            }
+
+            if need_publish_info {
+                match minimq.client().publish(
+                    Publication::new(config.firmware_info.as_bytes())
+                        .topic(topics.info)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => need_publish_info = false,
+                    Err(err) => log::warn!("Error publishing firmware info: {:?}", err),
+                }
+            }
+
+            if need_publish_location {
+                match minimq.client().publish(
+                    Publication::new(config.location.as_bytes())
+                        .topic(topics.location)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => need_publish_location = false,
+                    Err(err) => log::warn!("Error publishing location: {:?}", err),
+                }
+            }
+
+            // Reaching the broker is the last step of the self-check, so the result is complete
+            // now, unless the actuator has not checked the button yet.
+            if let (true, Some(button)) = (need_publish_selfcheck, state::button_selfcheck()) {
+                let leds = state::leds_read();
+                let wifi = network_stack.is_link_up();
+                let dhcp = network_stack.config_v4().is_some();
+                let passed = leds && button && wifi && dhcp;
+                let mut s = String::<160>::new();
+                // The longest possible output is 121 bytes, so it always fits.
+                write!(
+                    s,
+                    concat!(
+                        r#"{{"selfcheck":"{}","leds":{},"button":{},"#,
+                        r#""wifi":{},"dhcp":{},"broker":true,"connection_failures":{}}}"#
+                    ),
+                    if passed { "pass" } else { "fail" },
+                    leds,
+                    button,
+                    wifi,
+                    dhcp,
+                    connection_failures
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.selfcheck)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {
+                        need_publish_selfcheck = false;
+                        if !passed {
+                            mqtt_log!("Self-check failed: {}", s);
+                        }
+                    }
+                    Err(err) => log::warn!("Error publishing self-check: {:?}", err),
+                }
+            }
+
+            if need_publish_boot {
+                match minimq.client().publish(
+                    Publication::new(boot::timings_json().as_bytes())
+                        .topic(topics.boot)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => need_publish_boot = false,
+                    Err(err) => log::warn!("Error publishing boot timings: {:?}", err),
+                }
+            }
+
+            // Report the configured endpoint together with the address actually in use.
+            if need_publish_broker {
+                let mut s = String::<128>::new();
+                // The endpoint is the only unbounded part; cut the message if it is too long.
+                let _ = write!(
+                    s,
+                    r#"{{"configured":"{}","resolved":"{}:{}"}}"#,
+                    config.mqtt_endpoint_str, emb_endpoint.0, emb_endpoint.1
+                );
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.broker)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => need_publish_broker = false,
+                    Err(err) => log::warn!("Error publishing broker address: {:?}", err),
+                }
+            }
+
+            // Publish the flash write counts on connect and on changes, to watch the wear.
+            let flash_writes = crate::flash_config::write_counts();
+            if last_published_flash_writes != Some(flash_writes) {
+                let mut s = String::<48>::new();
+                // The longest possible output is 42 bytes, so it always fits.
+                write!(
+                    s,
+                    r#"{{"writes":{},"limited":{}}}"#,
+                    flash_writes.0, flash_writes.1
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.flash_writes)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => last_published_flash_writes = Some(flash_writes),
+                    Err(err) => log::warn!("Error publishing flash writes: {:?}", err),
+                }
+            }
+
+            // Announce the device on connect and periodically, for discovery by monitoring tools.
+            let announcement_due = match last_announcement {
+                None => true,
+                Some(last) => last.elapsed() > ANNOUNCEMENT_PERIOD,
+            };
+            if announcement_due {
+                match minimq.client().publish(
+                    Publication::new(config.mqtt_announcement.as_bytes())
+                        .topic(topics.announce)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => last_announcement = Some(Instant::now()),
+                    Err(err) => log::warn!("Error publishing announcement: {:?}", err),
+                }
+            }
+
+            // Publish the uptime in milliseconds, as seen by interop::Clock that minimq uses for
+            // its timeouts, to validate the timing against the broker's clock. There is no
+            // wall-clock time source to measure the drift against.
+            let clock_update_due = match last_clock_update {
+                None => true,
+                Some(last) => last.elapsed() > CLOCK_UPDATE_PERIOD,
+            };
+            if clock_update_due {
+                let mut s = String::<48>::new();
+                // The longest possible output is 34 bytes, so it always fits.
+                write!(s, r#"{{"uptime_ms":{}}}"#, Instant::now().as_millis()).unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.clock)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => last_clock_update = Some(Instant::now()),
+                    Err(err) => log::warn!("Error publishing clock: {:?}", err),
+                }
+            }
+
+            // Publish the WiFi signal strength. The control is not waited for if the roaming task
+            // holds it (for example, while scanning), so that the loop is not blocked; the update
+            // is retried on the next iteration instead.
+            let rssi_update_due = options.rssi_period.as_ticks() != 0
+                && match last_rssi_update {
+                    None => true,
+                    Some(last) => last.elapsed() > options.rssi_period,
+                };
+            if rssi_update_due {
+                if let Ok(mut control) = wifi_control.try_lock() {
+                    let rssi = control.get_rssi().await;
+                    drop(control);
+                    let mut s = String::<24>::new();
+                    // The longest possible output is 21 bytes, so it always fits.
+                    write!(s, r#"{{"rssi":{}}}"#, rssi).unwrap();
+                    match minimq.client().publish(
+                        Publication::new(s.as_bytes())
+                            .topic(topics.rssi)
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => last_rssi_update = Some(Instant::now()),
+                        Err(err) => log::warn!("Error publishing RSSI: {:?}", err),
+                    }
+                }
+            }
+
+            // Drain the logs channel and publish everything. Stop at the first failure (most
+            // likely, the minimq buffer is full), and retry the failed message on the next
+            // iteration, so that it is not lost. The remaining messages stay in the channel
+            // meanwhile.
+            if options.log_batch {
+                loop {
+                    // Append as many messages as fit; the one that does not fit starts the next
+                    // batch.
+                    while let Some(log_message) = pending_log
+                        .take()
+                        .or_else(|| log_receiver.try_receive().ok())
+                    {
+                        let separator = if log_batch.is_empty() { "" } else { "\n" };
+                        if log_batch.len() + separator.len() + log_message.len()
+                            > log_batch.capacity()
+                        {
+                            pending_log = Some(log_message);
+                            break;
+                        }
+                        // Cannot fail: the length is checked above.
+                        log_batch.push_str(separator).unwrap();
+                        log_batch.push_str(&log_message).unwrap();
+                    }
+                    if log_batch.is_empty() {
+                        break;
+                    }
+                    match minimq.client().publish(
+                        Publication::new(log_batch.as_bytes())
+                            .topic(topics.log)
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => log_batch.clear(),
+                        Err(err) => {
+                            log::warn!("Error publishing logs: {:?}", err);
+                            break;
+                        }
+                    }
+                }
+            } else {
+                while let Some(log_message) = pending_log
+                    .take()
+                    .or_else(|| log_receiver.try_receive().ok())
+                {
+                    match minimq.client().publish(
+                        Publication::new(log_message.as_bytes())
+                            .topic(topics.log)
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => {}
+                        Err(err) => {
+                            log::warn!("Error publishing logs: {:?}", err);
+                            pending_log = Some(log_message);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            // Publish all pending alerts. On failure, the alert is lost: it was already logged.
+            while let Ok(alert) = alert_receiver.try_receive() {
+                match minimq.client().publish(
+                    Publication::new(alert.as_bytes())
+                        .topic(topics.alert)
                         .finish()
                         .unwrap(),
                 ) {
                     Ok(()) => {}
-                    Err(err) => log::warn!("Error publishing logs: {:?}", err),
+                    Err(err) => log::warn!("Error publishing alert {}: {:?}", alert, err),
+                }
+            }
+
+            // Publish the recognised device events. On failure, the event is lost: it was already
+            // logged.
+            while let Some(event) = state::try_receive_event() {
+                match minimq.client().publish(
+                    Publication::new(event.as_bytes())
+                        .topic(topics.event)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => log::warn!("Error publishing event {}: {:?}", event, err),
+                }
+            }
+
+            // Publish the recorded LED changes, if tracing. Each message is `<uptime ms> <LLL>`,
+            // where each L is 1 if the corresponding (low, medium, high) LED is lit.
+            while let Some(event) = state::try_receive_trace_event() {
+                let mut s = String::<32>::new();
+                // The longest possible output is 24 bytes, so it always fits.
+                write!(
+                    s,
+                    "{} {}{}{}",
+                    event.at.as_millis(),
+                    event.leds[0] as u8,
+                    event.leds[1] as u8,
+                    event.leds[2] as u8
+                )
+                .unwrap();
+                match minimq.client().publish(
+                    Publication::new(s.as_bytes())
+                        .topic(topics.trace)
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => {}
+                    Err(err) => {
+                        // The trace is best-effort: drop this event, and publish the rest on the
+                        // next iteration.
+                        log::warn!("Error publishing trace: {:?}", err);
+                        break;
+                    }
                 }
             }
 
-            // if there was no state update for some time, or the state changed since the last
-            // update, publish it.
             let now = Instant::now();
-            let new_state = state::get_current_state(now).await;
-            if now.duration_since(last_published_state.0) > STATE_UPDATE_PERIOD
-                || (last_published_state.1 != new_state && new_state != state::DeviceState::Unknown)
+
+            // Make it obvious when the reported state is not the real one: publish the overriding
+            // state while the override is active, and `none` after it ends.
+            let state_override = state::get_state_override(now);
+            if last_published_override != Some(state_override) {
+                if state_override.is_none() && last_published_override.is_some() {
+                    mqtt_log!("State override has ended");
+                }
+                match minimq.client().publish(
+                    Publication::new(state_override.map_or(&b"none"[..], |s| s.as_bytes()))
+                        .topic(topics.state_override)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                ) {
+                    Ok(()) => last_published_override = Some(state_override),
+                    Err(err) => log::warn!("Error publishing state override: {:?}", err),
+                }
+            }
+
+            // Give up on the publication that the broker does not acknowledge, rather than let
+            // minimq hold it (and the following ones) in the buffer indefinitely.
+            if let Some((published_at, unacked)) = unacked_state {
+                if !minimq.client().pending_messages() {
+                    unacked_state = None;
+                } else if now.duration_since(published_at) > options.qos1_ack_timeout {
+                    log::warn!(
+                        "State {:?} was not acknowledged in {}s; dropping the connection",
+                        unacked,
+                        options.qos1_ack_timeout.as_secs()
+                    );
+                    // Unless the session is persistent, reconnecting makes minimq discard the
+                    // pending publications.
+                    socket.borrow_mut().abort();
+                    unacked_state = None;
+                }
+            }
+
+            // Publish the target state whenever it changes.
+            let target_state = state::get_target_state().await;
+            if last_published_target != Some(target_state) {
+                let mut publication =
+                    Publication::new(target_state.as_bytes()).topic(topics.target);
+                if options.target_retain {
+                    publication = publication.retain();
+                }
+                match minimq.client().publish(publication.finish().unwrap()) {
+                    Ok(()) => last_published_target = Some(target_state),
+                    Err(err) => log::info!("Error publishing target state: {:?}", err),
+                }
+            }
+
+            // Confirm that the commanded target state was reached, or report that it was not.
+            if let Some((commanded_at, target)) = pending_target {
+                let payload = if options.reached_timeout == Duration::from_ticks(0) {
+                    None
+                } else if target.is_reached(state::get_current_state(now)) {
+                    Some(target.as_bytes())
+                } else if now.duration_since(commanded_at) > options.reached_timeout {
+                    Some(&b"timeout"[..])
+                } else {
+                    None
+                };
+                if let Some(payload) = payload {
+                    match minimq.client().publish(
+                        Publication::new(payload)
+                            .topic(topics.reached)
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => pending_target = None,
+                        Err(err) => log::info!("Error publishing reached target: {:?}", err),
+                    }
+                }
+            }
+
+            // if there was no state update for some time (unless only changes are published), or
+            // the state changed since the last update, publish it. Nothing is published until the
+            // state reflects real readings.
+            let new_state = state::get_current_state(now);
+            // After an optimistic state, the observed one is published once it reaches the target,
+            // or once the hold ends, even if it has not changed, to correct the optimistic one.
+            let optimistic_ended = match optimistic {
+                Some((until, target)) if now < until && !target.is_reached(new_state) => false,
+                Some(_) => {
+                    optimistic = None;
+                    true
+                }
+                None => false,
+            };
+            if state::has_valid_reading(now)
+                && optimistic.is_none()
+                && !(options.suppress_flapping_state && state::is_flapping())
+                && (optimistic_ended
+                    || !boot_snapshot_published
+                    || (!options.state_on_change_only
+                        && now.duration_since(last_published_state.0) > STATE_UPDATE_PERIOD)
+                    || (last_published_state.1 != new_state
+                        && new_state != state::DeviceState::Unknown))
             {
+                // Annotate the change with its source. Not needed for periodic updates or the first
+                // known state.
+                if last_published_state.1 != new_state
+                    && last_published_state.1 != state::DeviceState::Unknown
+                {
+                    let source = state::change_source(last_published_state.1, new_state, now);
+                    let mut s = String::<64>::new();
+                    // The longest possible output is 47 bytes, so it always fits.
+                    write!(
+                        s,
+                        r#"{{"state":"{}","source":"{}"}}"#,
+                        new_state.as_str(),
+                        source.as_str()
+                    )
+                    .unwrap();
+                    match minimq.client().publish(
+                        Publication::new(s.as_bytes())
+                            .topic(topics.change)
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => {}
+                        Err(err) => log::info!("Error publishing state change: {:?}", err),
+                    }
+                }
+
                 match minimq.client().publish(
                     Publication::new(new_state.as_bytes())
                         .topic(topics.state)
+                        .qos(state_qos)
                         .retain()
                         .finish()
                         .unwrap(),
                 ) {
-                    Ok(()) => last_published_state = (now, new_state),
+                    Ok(()) => {
+                        last_published_state = (now, new_state);
+                        boot_snapshot_published = true;
+                        if options.state_qos1 && unacked_state.is_none() {
+                            unacked_state = Some((now, new_state));
+                        }
+                    }
                     Err(err) => log::info!("Error publishing state: {:?}", err),
                 }
+
+                if options.state_numeric {
+                    let mut s = String::<4>::new();
+                    // At most 2 digits, so it always fits.
+                    write!(s, "{}", new_state.as_numeric()).unwrap();
+                    match minimq.client().publish(
+                        Publication::new(s.as_bytes())
+                            .topic(topics.state_num)
+                            .retain()
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => {}
+                        Err(err) => log::info!("Error publishing numeric state: {:?}", err),
+                    }
+                }
+
+                if options.state_byte {
+                    match minimq.client().publish(
+                        Publication::new(&[new_state.as_numeric()][..])
+                            .topic(topics.state_byte)
+                            .retain()
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => {}
+                        Err(err) => log::info!("Error publishing state byte: {:?}", err),
+                    }
+                }
+
+                if let Some(percent) = new_state.as_percent().filter(|_| options.state_percent) {
+                    let mut s = String::<4>::new();
+                    // At most 3 digits, so it always fits.
+                    write!(s, "{}", percent).unwrap();
+                    match minimq.client().publish(
+                        Publication::new(s.as_bytes())
+                            .topic(topics.state_pct)
+                            .retain()
+                            .finish()
+                            .unwrap(),
+                    ) {
+                        Ok(()) => {}
+                        Err(err) => log::info!("Error publishing percentage state: {:?}", err),
+                    }
+                }
             }
         }
 
+        last_loop_time = loop_start.elapsed();
+        max_loop_time = max_loop_time.max(last_loop_time);
         ticker.next().await;
     }
 }
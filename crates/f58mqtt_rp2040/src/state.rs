@@ -1,10 +1,17 @@
 /// Interacts with the Flair58 heating device: detects its state from the LED changes, and
 /// manipulates the state by emulating the button press.
+use crate::config::{LedAdcConfig, RampConfig, SafetyConfig};
 use crate::mqtt_log;
-use embassy_rp::{gpio, peripherals};
+use core::cell::{Cell, RefCell};
+use embassy_rp::{adc, gpio, peripherals};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Instant, Timer};
+use heapless::{HistoryBuffer, Vec};
+use portable_atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 // Power levels of the device, as labelled on it.
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -14,6 +21,18 @@ pub(crate) enum PowerLevel {
     High,
 }
 
+impl PowerLevel {
+    // Represents the level as a percentage, for fan-like user interfaces. Maps back to the same
+    // level with TargetState::from_percent().
+    pub(crate) const fn as_percent(&self) -> u8 {
+        match self {
+            PowerLevel::Low => 33,
+            PowerLevel::Medium => 66,
+            PowerLevel::High => 100,
+        }
+    }
+}
+
 // The device state observed from LEDs.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) enum DeviceState {
@@ -32,25 +51,183 @@ pub(crate) enum DeviceState {
 }
 
 impl DeviceState {
+    // Represents the state as a string, for publishing in MQTT topic.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DeviceState::Off => "off",
+            DeviceState::Unknown => "unknown",
+            DeviceState::Heating(PowerLevel::Low) => "heating_low",
+            DeviceState::Heating(PowerLevel::Medium) => "heating_medium",
+            DeviceState::Heating(PowerLevel::High) => "heating_high",
+            DeviceState::On(PowerLevel::Low) => "on_low",
+            DeviceState::On(PowerLevel::Medium) => "on_medium",
+            DeviceState::On(PowerLevel::High) => "on_high",
+        }
+    }
+
     // Represents the state as a bytes string, for publishing in MQTT topic.
     pub(crate) fn as_bytes(&self) -> &'static [u8] {
+        self.as_str().as_bytes()
+    }
+
+    // Represents the state as a number, for charting. The tens encode the kind of the state, and
+    // the units encode the power level. The encoding is published, so it must stay stable:
+    // 0 = off, 1 = unknown, 10/11/12 = heating low/medium/high, 20/21/22 = on low/medium/high.
+    pub(crate) fn as_numeric(&self) -> u8 {
+        match self {
+            DeviceState::Off => 0,
+            DeviceState::Unknown => 1,
+            DeviceState::Heating(level) => 10 + *level as u8,
+            DeviceState::On(level) => 20 + *level as u8,
+        }
+    }
+
+    // Represents the state as a percentage, see PowerLevel::as_percent(). None if the state is
+    // unknown.
+    pub(crate) fn as_percent(&self) -> Option<u8> {
+        match self {
+            DeviceState::Off => Some(0),
+            DeviceState::Unknown => None,
+            DeviceState::Heating(level) | DeviceState::On(level) => Some(level.as_percent()),
+        }
+    }
+
+    // Parses the representation returned by as_bytes().
+    pub(crate) fn from_bytes(s: &[u8]) -> Option<DeviceState> {
+        match s {
+            b"off" => Some(DeviceState::Off),
+            b"unknown" => Some(DeviceState::Unknown),
+            b"heating_low" => Some(DeviceState::Heating(PowerLevel::Low)),
+            b"heating_medium" => Some(DeviceState::Heating(PowerLevel::Medium)),
+            b"heating_high" => Some(DeviceState::Heating(PowerLevel::High)),
+            b"on_low" => Some(DeviceState::On(PowerLevel::Low)),
+            b"on_medium" => Some(DeviceState::On(PowerLevel::Medium)),
+            b"on_high" => Some(DeviceState::On(PowerLevel::High)),
+            _ => None,
+        }
+    }
+}
+
+// Who caused a change of the observed device state.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum ChangeSource {
+    // The actuator pushed the button shortly before the change.
+    Mqtt,
+    // The device finished heating on its own.
+    Device,
+    // Nothing in the firmware explains the change, so someone pressed the button on the device.
+    Physical,
+}
+
+impl ChangeSource {
+    pub(crate) fn as_str(&self) -> &'static str {
         match self {
-            DeviceState::Off => b"off",
-            DeviceState::Unknown => b"unknown",
-            DeviceState::Heating(PowerLevel::Low) => b"heating_low",
-            DeviceState::Heating(PowerLevel::Medium) => b"heating_medium",
-            DeviceState::Heating(PowerLevel::High) => b"heating_high",
-            DeviceState::On(PowerLevel::Low) => b"on_low",
-            DeviceState::On(PowerLevel::Medium) => b"on_medium",
-            DeviceState::On(PowerLevel::High) => b"on_high",
+            ChangeSource::Mqtt => "mqtt",
+            ChangeSource::Device => "device",
+            ChangeSource::Physical => "physical",
         }
     }
 }
 
+// For how long after a button push the state changes are attributed to the push. Covers the time
+// the device takes to react to the push, and the time BLINK_DURATION takes to recognise the new
+// state.
+const PUSH_ATTRIBUTION_WINDOW: Duration = Duration::from_secs(15);
+
+// Time of the start of the last button push by the actuator, in ticks. Zero if there was no push.
+static LAST_PUSH: AtomicU64 = AtomicU64::new(0);
+
+// Returns who caused the observed state change from `from` to `to`, that was noticed at `now`.
+pub(crate) fn change_source(from: DeviceState, to: DeviceState, now: Instant) -> ChangeSource {
+    let last_push = LAST_PUSH.load(Ordering::Relaxed);
+    if last_push != 0
+        && now.duration_since(Instant::from_ticks(last_push)) < PUSH_ATTRIBUTION_WINDOW
+    {
+        return ChangeSource::Mqtt;
+    }
+    match (from, to) {
+        (DeviceState::Heating(x), DeviceState::On(y)) if x == y => ChangeSource::Device,
+        _ => ChangeSource::Physical,
+    }
+}
+
 // Returns the currently known state of the device. This function returns fast and does not perform
 // any IO.
-pub(crate) async fn get_current_state(now: Instant) -> DeviceState {
-    DEVICE_STATE_MANAGER.lock().await.state(now)
+pub(crate) fn get_current_state(now: Instant) -> DeviceState {
+    let observed = with_device_state_manager(|manager| manager.current_state(now));
+    get_state_override(now).unwrap_or(observed)
+}
+
+// The state reported instead of the observed one, and the time until which it is reported. For
+// testing automations without the physical device.
+static STATE_OVERRIDE: BlockingMutex<ThreadModeRawMutex, Cell<Option<(Instant, DeviceState)>>> =
+    BlockingMutex::new(Cell::new(None));
+
+// Makes get_current_state() return the given state instead of the observed one until `until`.
+pub(crate) fn set_state_override(state: DeviceState, until: Instant) {
+    STATE_OVERRIDE.lock(|state_override| state_override.set(Some((until, state))));
+}
+
+// Returns the state that overrides the observed one, if the override has not expired yet.
+pub(crate) fn get_state_override(now: Instant) -> Option<DeviceState> {
+    match STATE_OVERRIDE.lock(|state_override| state_override.get()) {
+        Some((until, state)) if now < until => Some(state),
+        _ => None,
+    }
+}
+
+// Returns how far the timing of each LED ([PowerLevel::Low, PowerLevel::Medium, PowerLevel::High])
+// is from being classified differently, and BLINK_DURATION, which caps the margins. Small margins
+// mean that BLINK_DURATION needs tuning for the device.
+pub(crate) fn blink_margins(now: Instant) -> ([Duration; 3], Duration) {
+    let margins = with_device_state_manager(|manager| manager.blink_margins(now));
+    (margins, blink_duration())
+}
+
+// Maximal number of state changes that can be required to recognise flapping.
+pub(crate) const MAX_FLAP_THRESHOLD: usize = 32;
+// The observed state is flapping if it changes FLAP_THRESHOLD times within FLAP_WINDOW. Zero
+// threshold disables the detection.
+static FLAP_THRESHOLD: AtomicU32 = AtomicU32::new(0);
+static FLAP_WINDOW_TICKS: AtomicU64 = AtomicU64::new(0);
+static FLAPPING: AtomicBool = AtomicBool::new(false);
+
+// Enables the flap detection with the given limits. Must be called before the state is observed.
+pub(crate) fn set_flap_limits(threshold: u32, window: Duration) {
+    FLAP_WINDOW_TICKS.store(window.as_ticks(), Ordering::Relaxed);
+    FLAP_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+// Returns whether the observed state is flapping, which suggests noisy wiring or a device fault.
+pub(crate) fn is_flapping() -> bool {
+    FLAPPING.load(Ordering::Relaxed)
+}
+
+// Time of the first LED reading by the detector, in ticks. Zero if there was no reading yet.
+static FIRST_READING_AT: AtomicU64 = AtomicU64::new(0);
+
+// Returns whether the observed state reflects real LED readings. Before that, the state is derived
+// from the default LED states (all off). After the first reading, blinks are recognised only once
+// BLINK_DURATION has passed.
+pub(crate) fn has_valid_reading(now: Instant) -> bool {
+    match FIRST_READING_AT.load(Ordering::Relaxed) {
+        0 => false,
+        first => now.duration_since(Instant::from_ticks(first)) > blink_duration(),
+    }
+}
+
+// Returns whether the LED inputs were read at least once.
+pub(crate) fn leds_read() -> bool {
+    FIRST_READING_AT.load(Ordering::Relaxed) != 0
+}
+
+// Number of the last state changes that are remembered.
+const STATE_HISTORY_SIZE: usize = 8;
+
+// Returns the last observed state changes with the times they were observed, from the oldest to the
+// newest.
+pub(crate) fn get_state_history() -> Vec<(Instant, DeviceState), STATE_HISTORY_SIZE> {
+    with_device_state_manager(|manager| manager.history.oldest_ordered().copied().collect())
 }
 
 // Target state for the device, to be set by emulating a button press.
@@ -62,15 +239,280 @@ pub(crate) enum TargetState {
     On(PowerLevel),
 }
 
+impl TargetState {
+    // Returns the next higher target state; the highest one stays as is.
+    const fn step_up(self) -> TargetState {
+        match self {
+            TargetState::Off => TargetState::On(PowerLevel::Low),
+            TargetState::On(PowerLevel::Low) => TargetState::On(PowerLevel::Medium),
+            TargetState::On(PowerLevel::Medium) | TargetState::On(PowerLevel::High) => {
+                TargetState::On(PowerLevel::High)
+            }
+        }
+    }
+
+    // Returns the next lower target state; the lowest one (Off) stays as is.
+    const fn step_down(self) -> TargetState {
+        match self {
+            TargetState::Off | TargetState::On(PowerLevel::Low) => TargetState::Off,
+            TargetState::On(PowerLevel::Medium) => TargetState::On(PowerLevel::Low),
+            TargetState::On(PowerLevel::High) => TargetState::On(PowerLevel::Medium),
+        }
+    }
+
+    // Maps a percentage to a target state: 0 is Off, 1-33 is Low, 34-66 is Medium, and 67-100 is
+    // High. Returns None for percentages above 100.
+    pub(crate) const fn from_percent(percent: u8) -> Option<TargetState> {
+        match percent {
+            0 => Some(TargetState::Off),
+            1..=33 => Some(TargetState::On(PowerLevel::Low)),
+            34..=66 => Some(TargetState::On(PowerLevel::Medium)),
+            67..=100 => Some(TargetState::On(PowerLevel::High)),
+            _ => None,
+        }
+    }
+
+    // Returns whether the device in the given state has reached this target state.
+    pub(crate) fn is_reached(&self, state: DeviceState) -> bool {
+        match (self, state) {
+            (TargetState::Off, DeviceState::Off) => true,
+            (TargetState::On(x), DeviceState::Heating(y) | DeviceState::On(y)) => *x == y,
+            _ => false,
+        }
+    }
+
+    // Returns the state the device is expected to show soon after being commanded to this target
+    // state: a level that is turned on heats up first.
+    pub(crate) fn expected_state(&self) -> DeviceState {
+        match self {
+            TargetState::Off => DeviceState::Off,
+            TargetState::On(level) => DeviceState::Heating(*level),
+        }
+    }
+
+    // Represents the target state as a string, for publishing in MQTT topic. Matches the commands
+    // accepted on the set topic.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TargetState::Off => "off",
+            TargetState::On(PowerLevel::Low) => "low",
+            TargetState::On(PowerLevel::Medium) => "medium",
+            TargetState::On(PowerLevel::High) => "high",
+        }
+    }
+
+    // Represents the target state as a bytes string, for publishing in MQTT topic.
+    pub(crate) fn as_bytes(&self) -> &'static [u8] {
+        self.as_str().as_bytes()
+    }
+}
+
+// The published percentages must be accepted back as commands for the same level.
+const _: () = {
+    assert!(matches!(
+        TargetState::from_percent(PowerLevel::Low.as_percent()),
+        Some(TargetState::On(PowerLevel::Low))
+    ));
+    assert!(matches!(
+        TargetState::from_percent(PowerLevel::Medium.as_percent()),
+        Some(TargetState::On(PowerLevel::Medium))
+    ));
+    assert!(matches!(
+        TargetState::from_percent(PowerLevel::High.as_percent()),
+        Some(TargetState::On(PowerLevel::High))
+    ));
+};
+
+// Stepping goes through every level in order, and stops at both ends.
+const _: () = {
+    assert!(matches!(
+        TargetState::Off.step_up(),
+        TargetState::On(PowerLevel::Low)
+    ));
+    assert!(matches!(
+        TargetState::On(PowerLevel::Low).step_up(),
+        TargetState::On(PowerLevel::Medium)
+    ));
+    assert!(matches!(
+        TargetState::On(PowerLevel::Medium).step_up(),
+        TargetState::On(PowerLevel::High)
+    ));
+    assert!(matches!(
+        TargetState::On(PowerLevel::High).step_up(),
+        TargetState::On(PowerLevel::High)
+    ));
+    assert!(matches!(
+        TargetState::On(PowerLevel::High).step_down(),
+        TargetState::On(PowerLevel::Medium)
+    ));
+    assert!(matches!(
+        TargetState::On(PowerLevel::Medium).step_down(),
+        TargetState::On(PowerLevel::Low)
+    ));
+    assert!(matches!(
+        TargetState::On(PowerLevel::Low).step_down(),
+        TargetState::Off
+    ));
+    assert!(matches!(TargetState::Off.step_down(), TargetState::Off));
+};
+
+// Returns the current target state.
+pub(crate) async fn get_target_state() -> TargetState {
+    *TARGET_STATE.lock().await
+}
+
 // Sets the target state. This function returns fast and does not perform the state actuation: it is
 // done in a different background task.
 pub(crate) async fn set_target_state(state: TargetState) {
-    *TARGET_STATE.lock().await = state;
+    let mut target_state = TARGET_STATE.lock().await;
+    *target_state = state;
+    cancel_boost();
+    target_state_changed();
+}
+
+// Moves the target state one step up (Off -> Low -> Medium -> High) or down, and returns the new
+// target state. Like set_target_state(), does not perform the state actuation.
+pub(crate) async fn step_target_state(up: bool) -> TargetState {
+    let new_state = {
+        let mut target_state = TARGET_STATE.lock().await;
+        *target_state = if up {
+            target_state.step_up()
+        } else {
+            target_state.step_down()
+        };
+        cancel_boost();
+        *target_state
+    };
+    target_state_changed();
+    new_state
+}
+
+fn target_state_changed() {
+    TARGET_GENERATION.add(1, Ordering::Relaxed);
+    record_activity(Instant::now());
+    WAKE_UP.signal(());
+    TARGET_CHANGED.signal(());
+}
+
+// Incremented on every command that changes the target state, so that the actuator can tell that a
+// command has arrived since some point.
+static TARGET_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+// Active boost: until when it lasts, and the target state to restore afterwards. Only changed with
+// TARGET_STATE locked, so that restoring the target cannot override a newer command.
+static BOOST: BlockingMutex<ThreadModeRawMutex, Cell<Option<(Instant, TargetState)>>> =
+    BlockingMutex::new(Cell::new(None));
+
+// Sets the target state to the highest level for the given duration, after which the actuator
+// restores the current target. Boosting during a boost extends it, still restoring the target from
+// before the first boost. Any other change of the target cancels the boost. Returns the target
+// state that will be restored.
+pub(crate) async fn start_boost(duration: Duration) -> TargetState {
+    let mut target_state = TARGET_STATE.lock().await;
+    let restored = match BOOST.lock(|boost| boost.get()) {
+        Some((_, restored)) => restored,
+        None => *target_state,
+    };
+    BOOST.lock(|boost| boost.set(Some((Instant::now() + duration, restored))));
+    *target_state = TargetState::On(PowerLevel::High);
+    target_state_changed();
+    restored
+}
+
+// Must be called with TARGET_STATE locked.
+fn cancel_boost() {
+    BOOST.lock(|boost| boost.set(None));
 }
 
-// Duration after which the LED is considered not blinking and steady.
-const BLINK_DURATION: Duration = Duration::from_millis(900);
+// Returns the target state to restore if the boost has ended by now. The times are in ticks.
+const fn expired_boost(boost: Option<(u64, TargetState)>, now: u64) -> Option<TargetState> {
+    match boost {
+        Some((until, restored)) if now >= until => Some(restored),
+        _ => None,
+    }
+}
+
+// A boost ends exactly at its end time, and no boost never ends.
+const _: () = {
+    let boost = Some((1000, TargetState::On(PowerLevel::Low)));
+    assert!(expired_boost(boost, 999).is_none());
+    assert!(matches!(
+        expired_boost(boost, 1000),
+        Some(TargetState::On(PowerLevel::Low))
+    ));
+    assert!(matches!(
+        expired_boost(boost, 5000),
+        Some(TargetState::On(PowerLevel::Low))
+    ));
+    assert!(matches!(
+        expired_boost(Some((0, TargetState::Off)), 0),
+        Some(TargetState::Off)
+    ));
+    assert!(expired_boost(None, u64::MAX).is_none());
+};
+
+// Restores the target state if the boost has ended, and returns it. Restoring is a change of the
+// target like a command, so the actuator starts counting its pushes anew.
+async fn end_expired_boost(now: Instant) -> Option<TargetState> {
+    let mut target_state = TARGET_STATE.lock().await;
+    let boost = BOOST.lock(|boost| boost.get());
+    let restored = expired_boost(
+        boost.map(|(until, restored)| (until.as_ticks(), restored)),
+        now.as_ticks(),
+    )?;
+    *target_state = restored;
+    cancel_boost();
+    target_state_changed();
+    Some(restored)
+}
+
+// Signalled when the target state changes, so that an idle actuator acts on it immediately.
+// Targets are coalesced rather than queued: the device can only be driven towards one state, so
+// when several commands arrive while the actuator is busy pushing the button, it skips the
+// intermediate targets and heads for the latest one as soon as the push settles.
+static TARGET_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+// Time of the last LED change or command, in ticks.
+static LAST_ACTIVITY: AtomicU64 = AtomicU64::new(0);
+// Signalled on commands and LED changes, to get the actuator out of the low-power mode immediately.
+static WAKE_UP: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+fn record_activity(now: Instant) {
+    LAST_ACTIVITY.store(now.as_ticks(), Ordering::Relaxed);
+}
+
+// Returns whether the device is in the low-power mode: the mode is enabled (low_power_after is
+// not zero), and nothing happened for low_power_after.
+pub(crate) fn is_idle(now: Instant, low_power_after: Duration) -> bool {
+    low_power_after != Duration::from_ticks(0)
+        && now.duration_since(Instant::from_ticks(LAST_ACTIVITY.load(Ordering::Relaxed)))
+            > low_power_after
+}
+
+// Duration after which the LED is considered not blinking and steady. Can be adjusted at runtime
+// with set_blink_duration().
+const DEFAULT_BLINK_DURATION: Duration = Duration::from_millis(900);
+// Allowed range for set_blink_duration().
+const MIN_BLINK_DURATION: Duration = Duration::from_millis(100);
+const MAX_BLINK_DURATION: Duration = Duration::from_millis(5000);
+
+// The effective BLINK_DURATION, in ticks.
+static BLINK_DURATION_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_BLINK_DURATION.as_ticks());
 
+fn blink_duration() -> Duration {
+    Duration::from_ticks(BLINK_DURATION_TICKS.load(Ordering::Relaxed))
+}
+
+// Changes BLINK_DURATION, if it is within the allowed range.
+pub(crate) fn set_blink_duration(duration: Duration) -> Result<(), &'static str> {
+    if !(MIN_BLINK_DURATION..=MAX_BLINK_DURATION).contains(&duration) {
+        return Err("out of the allowed range [100, 5000]ms");
+    }
+    BLINK_DURATION_TICKS.store(duration.as_ticks(), Ordering::Relaxed);
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
 enum LedState {
     // Off for at least BLINK_DURATION.
     Off,
@@ -80,8 +522,44 @@ enum LedState {
     Blinking,
 }
 
+impl LedState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LedState::Off => "off",
+            LedState::On => "on",
+            LedState::Blinking => "blink",
+        }
+    }
+}
+
+// States of all LEDs, for diagnostics. Displayed as `[<low>,<medium>,<high>]`.
+#[derive(Clone, Copy)]
+pub(crate) struct LedPattern([LedState; 3]);
+
+impl core::fmt::Display for LedPattern {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[{},{},{}]",
+            self.0[0].as_str(),
+            self.0[1].as_str(),
+            self.0[2].as_str()
+        )
+    }
+}
+
+// The LED pattern that produced the last DeviceState::Unknown. A blocking mutex, so that it can be
+// read from the synchronous track_unknown_state().
+static LAST_UNKNOWN_PATTERN: BlockingMutex<ThreadModeRawMutex, Cell<Option<LedPattern>>> =
+    BlockingMutex::new(Cell::new(None));
+
+// Returns the LED pattern that produced the last DeviceState::Unknown, if any.
+pub(crate) fn last_unknown_pattern() -> Option<LedPattern> {
+    LAST_UNKNOWN_PATTERN.lock(|pattern| pattern.get())
+}
+
 fn led_state((last_instant, last_level): &(Instant, gpio::Level), now: Instant) -> LedState {
-    if now.duration_since(*last_instant) > BLINK_DURATION {
+    if now.duration_since(*last_instant) > blink_duration() {
         match last_level {
             gpio::Level::Low => LedState::Off,
             gpio::Level::High => LedState::On,
@@ -95,31 +573,154 @@ fn led_state((last_instant, last_level): &(Instant, gpio::Level), now: Instant)
 // based on this.
 struct DeviceStateManager {
     leds: [(Instant, gpio::Level); 3], // [PowerLevel::Low, PowerLevel::Medium, PowerLevel::High].
+    // The last observed state changes, with the times they were observed.
+    history: HistoryBuffer<(Instant, DeviceState), STATE_HISTORY_SIZE>,
+    // Since when all LEDs are lit, if this may be the preheat complete flash.
+    flash_since: Option<Instant>,
+    // For how long each LED kept its level before the last change, for blink_margins().
+    last_intervals: [Duration; 3],
+    // Times of the last state changes, for flap detection.
+    transitions: HistoryBuffer<Instant, MAX_FLAP_THRESHOLD>,
 }
 
-static DEVICE_STATE_MANAGER: Mutex<ThreadModeRawMutex, DeviceStateManager> =
-    Mutex::new(DeviceStateManager::new());
+// A blocking mutex, so that it cannot be held across an await point: the detectors and the readers
+// never wait for each other, as every access completes without yielding to the executor.
+static DEVICE_STATE_MANAGER: BlockingMutex<ThreadModeRawMutex, RefCell<DeviceStateManager>> =
+    BlockingMutex::new(RefCell::new(DeviceStateManager::new()));
+
+fn with_device_state_manager<R>(f: impl FnOnce(&mut DeviceStateManager) -> R) -> R {
+    DEVICE_STATE_MANAGER.lock(|manager| f(&mut manager.borrow_mut()))
+}
 
 impl DeviceStateManager {
     const fn new() -> DeviceStateManager {
         DeviceStateManager {
             leds: [(Instant::MIN, gpio::Level::Low); 3],
+            history: HistoryBuffer::new(),
+            flash_since: None,
+            last_intervals: [Duration::from_ticks(0); 3],
+            transitions: HistoryBuffer::new(),
+        }
+    }
+
+    // Returns the current state, remembering the LED pattern if it is unknown.
+    fn current_state(&self, now: Instant) -> DeviceState {
+        let state = self.state(now);
+        if state == DeviceState::Unknown {
+            let pattern = self.led_pattern(now);
+            LAST_UNKNOWN_PATTERN.lock(|last| last.set(Some(pattern)));
         }
+        state
     }
 
-    fn update(&mut self, led: PowerLevel, level: gpio::Level, now: Instant) {
+    // Records the current state in the history, and counts it for the flap detection, if it has
+    // changed. Called on every LED reading, and when a blink may have timed out, so that neither
+    // depends on how often the state is polled. Returns whether the state has started or stopped
+    // flapping, if it has.
+    fn observe(&mut self, now: Instant) -> Option<bool> {
+        let state = self.current_state(now);
+        if self.history.recent().map(|(_, last)| *last) != Some(state) {
+            self.history.write((now, state));
+            self.transitions.write(now);
+        }
+        self.check_flapping(now)
+    }
+
+    // Returns whether the state has started flapping (changed FLAP_THRESHOLD times within
+    // FLAP_WINDOW) or stopped, if it has.
+    fn check_flapping(&self, now: Instant) -> Option<bool> {
+        let threshold = FLAP_THRESHOLD.load(Ordering::Relaxed) as usize;
+        if threshold == 0 {
+            return None;
+        }
+        let window = Duration::from_ticks(FLAP_WINDOW_TICKS.load(Ordering::Relaxed));
+        let recent = self
+            .transitions
+            .iter()
+            .filter(|at| now.duration_since(**at) <= window)
+            .count();
+        let flapping = recent >= threshold;
+        (FLAPPING.swap(flapping, Ordering::Relaxed) != flapping).then_some(flapping)
+    }
+
+    // Returns when the last LED change times out, after which the LED is steady and the state may
+    // change without any LED change. None if all LEDs are steady already.
+    fn blink_timeout(&self, now: Instant) -> Option<Instant> {
+        let blink = blink_duration();
+        self.leds
+            .iter()
+            // led_state() considers the LED steady once strictly more than BLINK_DURATION passed.
+            .map(|(since, _)| *since + blink + Duration::from_ticks(1))
+            .filter(|at| *at > now)
+            .min()
+    }
+
+    // Returns whether the LED level has changed.
+    fn update(&mut self, led: PowerLevel, level: gpio::Level, now: Instant) -> bool {
         let last = &mut self.leds[led as usize];
         if last.1 != level {
+            self.last_intervals[led as usize] = now.duration_since(last.0);
             *last = (now, level);
+            true
+        } else {
+            false
         }
     }
 
-    fn state(&self, now: Instant) -> DeviceState {
-        match (
+    // Recognises the preheat complete signal: all LEDs light up at once for at most `flash_max`.
+    // Must be called with the new levels before update(). Returns whether the flash has just ended.
+    fn track_flash(&mut self, levels: [gpio::Level; 3], now: Instant, flash_max: Duration) -> bool {
+        let all_lit = levels == [gpio::Level::High; 3];
+        match self.flash_since {
+            // When heating at the high level, all LEDs are lit with every blink too, but the low
+            // and the medium LEDs are lit already before it.
+            None if all_lit
+                && !(self.leds[0].1 == gpio::Level::High
+                    && self.leds[1].1 == gpio::Level::High) =>
+            {
+                self.flash_since = Some(now);
+                false
+            }
+            Some(since) if !all_lit => {
+                self.flash_since = None;
+                now.duration_since(since) <= flash_max
+            }
+            _ => false,
+        }
+    }
+
+    // Returns how far the timing of each LED is from BLINK_DURATION, capped at BLINK_DURATION. A
+    // steady LED is as far as it has been steady for longer than BLINK_DURATION. A blinking LED is
+    // as far as the longer of its current and its last interval is shorter than BLINK_DURATION.
+    fn blink_margins(&self, now: Instant) -> [Duration; 3] {
+        let blink = blink_duration();
+        let mut margins = [Duration::from_ticks(0); 3];
+        for (margin, ((since, _), last_interval)) in margins
+            .iter_mut()
+            .zip(self.leds.iter().zip(self.last_intervals))
+        {
+            let elapsed = now.duration_since(*since);
+            *margin = if elapsed > blink {
+                elapsed - blink
+            } else {
+                blink - elapsed.max(last_interval.min(blink))
+            }
+            .min(blink);
+        }
+        margins
+    }
+
+    fn led_pattern(&self, now: Instant) -> LedPattern {
+        LedPattern([
             led_state(&self.leds[0], now),
             led_state(&self.leds[1], now),
             led_state(&self.leds[2], now),
-        ) {
+        ])
+    }
+
+    fn state(&self, now: Instant) -> DeviceState {
+        let LedPattern([low, medium, high]) = self.led_pattern(now);
+        match (low, medium, high) {
             (LedState::Off, LedState::Off, LedState::Off) => DeviceState::Off,
             (LedState::On, LedState::Off, LedState::Off) => DeviceState::On(PowerLevel::Low),
             (LedState::On, LedState::On, LedState::Off) => DeviceState::On(PowerLevel::Medium),
@@ -138,8 +739,55 @@ impl DeviceStateManager {
     }
 }
 
-// How often the LEDs should be polled, to ensure that blinks are properly recognised.
-const POLL_PERIOD: Duration = Duration::from_millis(BLINK_DURATION.as_millis() / 2 - 50);
+// Poll period in the trace mode, to catch fast transient LED changes.
+const TRACE_POLL_PERIOD: Duration = Duration::from_millis(20);
+// For how long the trace mode stays enabled.
+const TRACE_DURATION: Duration = Duration::from_secs(60);
+
+// A raw LED change, recorded in the trace mode.
+pub(crate) struct TraceEvent {
+    pub at: Instant,
+    // Whether each LED is lit: [PowerLevel::Low, PowerLevel::Medium, PowerLevel::High].
+    pub leds: [bool; 3],
+}
+
+// Time when the trace mode ends, in ticks. Zero if the trace mode was never enabled.
+static TRACE_UNTIL: AtomicU64 = AtomicU64::new(0);
+// Events exceeding the capacity are dropped, which bounds the rate of trace publications.
+static TRACE_CHANNEL: Channel<ThreadModeRawMutex, TraceEvent, 32> = Channel::new();
+
+// Enables the trace mode, in which every LED change is recorded, for TRACE_DURATION.
+pub(crate) fn start_trace(now: Instant) -> Duration {
+    start_trace_until(now + TRACE_DURATION);
+    TRACE_DURATION
+}
+
+// Enables the trace mode until the given time.
+pub(crate) fn start_trace_until(until: Instant) {
+    TRACE_UNTIL.store(until.as_ticks(), Ordering::Relaxed);
+}
+
+// Disables the trace mode early.
+pub(crate) fn stop_trace() {
+    TRACE_UNTIL.store(0, Ordering::Relaxed);
+}
+
+fn is_tracing(now: Instant) -> bool {
+    now.as_ticks() < TRACE_UNTIL.load(Ordering::Relaxed)
+}
+
+// Returns the next recorded trace event, if any.
+pub(crate) fn try_receive_trace_event() -> Option<TraceEvent> {
+    TRACE_CHANNEL.try_receive().ok()
+}
+
+// Transient signals of the device that are not states, like `preheat_complete`.
+static EVENT_CHANNEL: Channel<ThreadModeRawMutex, &'static str, 4> = Channel::new();
+
+// Returns the next recognised device event, if any.
+pub(crate) fn try_receive_event() -> Option<&'static str> {
+    EVENT_CHANNEL.try_receive().ok()
+}
 
 // Polls LEDs over GPIO and logs the result to the DeviceStateManager.
 #[embassy_executor::task]
@@ -147,134 +795,1343 @@ pub(super) async fn led_detector_task(
     pin_low: peripherals::PIN_12,
     pin_medium: peripherals::PIN_13,
     pin_high: peripherals::PIN_14,
+    pulls: [gpio::Pull; 3],
+    preheat_flash_max: Duration,
+    backstop_poll: Duration,
 ) -> ! {
-    let mut pin_low = gpio::Input::new(pin_low, gpio::Pull::Down);
-    let mut pin_medium = gpio::Input::new(pin_medium, gpio::Pull::Down);
-    let mut pin_high = gpio::Input::new(pin_high, gpio::Pull::Down);
+    let mut pin_low = gpio::Input::new(pin_low, pulls[0]);
+    let mut pin_medium = gpio::Input::new(pin_medium, pulls[1]);
+    let mut pin_high = gpio::Input::new(pin_high, pulls[2]);
 
     loop {
+        let levels = [
+            pin_low.get_level(),
+            pin_medium.get_level(),
+            pin_high.get_level(),
+        ];
+        let blink_timeout = record_levels(levels, preheat_flash_max);
+
+        // Waiting for the level opposite to the recorded one (rather than for an edge) does not
+        // miss a change that happened between the reading and the start of the wait. The timer
+        // records a blink that has timed out, and is otherwise only a backstop, and the fast poll
+        // in the trace mode.
+        let mut wake_at = Instant::now()
+            + if is_tracing(Instant::now()) {
+                TRACE_POLL_PERIOD
+            } else {
+                backstop_poll
+            };
+        if let Some(at) = blink_timeout {
+            wake_at = wake_at.min(at);
+        }
         embassy_futures::select::select4(
-            pin_low.wait_for_any_edge(),
-            pin_medium.wait_for_any_edge(),
-            pin_high.wait_for_any_edge(),
-            // wait_for_any_edge might be racy if the pin changed its state between the last
-            // get_level call and the start of the wait_for_any_edge call. So explicitly poll all
-            // pins every 400 milliseconds nevertheless.
-            // TODO: This can be rewritten to check the last state known to state_manager and
-            // waiting for an opposite value (wait_for_high / wait_for_low) in select4() instead.
-            Timer::after(POLL_PERIOD),
+            wait_for_change(&mut pin_low, levels[0]),
+            wait_for_change(&mut pin_medium, levels[1]),
+            wait_for_change(&mut pin_high, levels[2]),
+            Timer::at(wake_at),
         )
         .await;
+    }
+}
 
-        {
-            let mut device_state_manager = DEVICE_STATE_MANAGER.lock().await;
-            let now = Instant::now();
-            device_state_manager.update(PowerLevel::Low, pin_low.get_level(), now);
-            device_state_manager.update(PowerLevel::Medium, pin_medium.get_level(), now);
-            device_state_manager.update(PowerLevel::High, pin_high.get_level(), now);
+// Records the current LED levels, read by either of the detectors, and the state they show.
+// Returns when the levels should be read again even if they do not change, see
+// DeviceStateManager::blink_timeout().
+fn record_levels(levels: [gpio::Level; 3], preheat_flash_max: Duration) -> Option<Instant> {
+    let (blink_timeout, flapping) = with_device_state_manager(|manager| {
+        record_levels_locked(manager, levels, preheat_flash_max)
+    });
+    // Reported with the lock released, so that publishing does not happen inside it.
+    match flapping {
+        Some(true) => crate::mqtt_alert("flapping"),
+        Some(false) => mqtt_log!("The state has stopped flapping"),
+        None => {}
+    }
+    blink_timeout
+}
+
+// Returns DeviceStateManager::blink_timeout(), and whether the state has started or stopped
+// flapping.
+fn record_levels_locked(
+    device_state_manager: &mut DeviceStateManager,
+    levels: [gpio::Level; 3],
+    preheat_flash_max: Duration,
+) -> (Option<Instant>, Option<bool>) {
+    let now = Instant::now();
+    if FIRST_READING_AT.load(Ordering::Relaxed) == 0 {
+        FIRST_READING_AT.store(now.as_ticks(), Ordering::Relaxed);
+    }
+    if preheat_flash_max != Duration::from_ticks(0)
+        && device_state_manager.track_flash(levels, now, preheat_flash_max)
+    {
+        mqtt_log!("Preheat complete");
+        if EVENT_CHANNEL.try_send("preheat_complete").is_err() {
+            log::warn!("^ the event above was not sent to mqtt");
+        }
+    }
+    // Non-short-circuiting `|`, so that all LEDs are updated.
+    if device_state_manager.update(PowerLevel::Low, levels[0], now)
+        | device_state_manager.update(PowerLevel::Medium, levels[1], now)
+        | device_state_manager.update(PowerLevel::High, levels[2], now)
+    {
+        record_activity(now);
+        WAKE_UP.signal(());
+        if is_tracing(now) {
+            // Dropping the event if the channel is full is intended.
+            let _ = TRACE_CHANNEL.try_send(TraceEvent {
+                at: now,
+                leds: levels.map(|level| level == gpio::Level::High),
+            });
+        }
+    }
+    let flapping = device_state_manager.observe(now);
+    (device_state_manager.blink_timeout(now), flapping)
+}
+
+// Number of ADC samples averaged into one brightness reading, and the time between them. The
+// window spans several periods of the PWM that some devices dim the LEDs with.
+const ADC_SAMPLES: u32 = 16;
+const ADC_SAMPLE_PERIOD: Duration = Duration::from_millis(1);
+// Time between the brightness readings.
+const ADC_POLL_PERIOD: Duration = Duration::from_millis(50);
+
+// Brightness of an LED measured through the ADC.
+enum Brightness {
+    Off,
+    // Lit, but dimmed by PWM.
+    Dim,
+    On,
+}
+
+impl Brightness {
+    // Dimmed LEDs are considered lit.
+    fn as_level(self) -> gpio::Level {
+        match self {
+            Brightness::Off => gpio::Level::Low,
+            Brightness::Dim | Brightness::On => gpio::Level::High,
         }
     }
 }
 
+fn classify_brightness(average: u16, config: &LedAdcConfig) -> Brightness {
+    if average >= config.on_threshold {
+        Brightness::On
+    } else if average >= config.dim_threshold {
+        Brightness::Dim
+    } else {
+        Brightness::Off
+    }
+}
+
+// Alternative to led_detector_task() for LEDs tapped through voltage dividers into the ADC pins.
+// Measures the average brightness of each LED rather than detecting edges, so that PWM dimming is
+// not mistaken for blinking. Dimmed LEDs are considered lit, and the readings are classified by the
+// same DeviceStateManager.
+#[embassy_executor::task]
+pub(super) async fn adc_led_detector_task(
+    adc: peripherals::ADC,
+    pin_low: peripherals::PIN_26,
+    pin_medium: peripherals::PIN_27,
+    pin_high: peripherals::PIN_28,
+    config: &'static LedAdcConfig,
+    preheat_flash_max: Duration,
+) -> ! {
+    let mut adc = adc::Adc::new_blocking(adc, adc::Config::default());
+    let mut channels = [
+        adc::Channel::new_pin(pin_low, gpio::Pull::None),
+        adc::Channel::new_pin(pin_medium, gpio::Pull::None),
+        adc::Channel::new_pin(pin_high, gpio::Pull::None),
+    ];
+
+    loop {
+        // Sums and numbers of the successful samples of each channel.
+        let mut sums = [0u32; 3];
+        let mut counts = [0u32; 3];
+        for _ in 0..ADC_SAMPLES {
+            for ((sum, count), channel) in sums
+                .iter_mut()
+                .zip(counts.iter_mut())
+                .zip(channels.iter_mut())
+            {
+                match adc.blocking_read(channel) {
+                    Ok(sample) => {
+                        *sum += u32::from(sample);
+                        *count += 1;
+                    }
+                    Err(err) => log::warn!("Cannot read ADC: {:?}", err),
+                }
+            }
+            Timer::after(ADC_SAMPLE_PERIOD).await;
+        }
+        // A channel without a single sample keeps its last recorded level, rather than reading as
+        // off.
+        if counts.contains(&0) {
+            log::warn!("No ADC samples for some LED; skipping the reading");
+        } else {
+            let mut levels = [gpio::Level::Low; 3];
+            for ((level, sum), count) in levels.iter_mut().zip(sums).zip(counts) {
+                *level = classify_brightness((sum / count) as u16, config).as_level();
+            }
+            // Polled much more often than BLINK_DURATION, so the blinks that time out are recorded
+            // without waking up for them.
+            record_levels(levels, preheat_flash_max);
+        }
+
+        Timer::after(if is_tracing(Instant::now()) {
+            TRACE_POLL_PERIOD
+        } else {
+            ADC_POLL_PERIOD
+        })
+        .await;
+    }
+}
+
+// Waits until the pin level differs from `last_level`.
+async fn wait_for_change(pin: &mut gpio::Input<'_>, last_level: gpio::Level) {
+    match last_level {
+        gpio::Level::Low => pin.wait_for_high().await,
+        gpio::Level::High => pin.wait_for_low().await,
+    }
+}
+
 static TARGET_STATE: Mutex<ThreadModeRawMutex, TargetState> = Mutex::new(TargetState::Off);
 
+// Set by the refresh command to restart the max_on safety timer.
+static SAFETY_TIMER_REFRESHED: AtomicBool = AtomicBool::new(false);
+
+// Restarts the timer after which the device is turned off for safety.
+pub(crate) fn refresh_safety_timer() {
+    SAFETY_TIMER_REFRESHED.store(true, Ordering::Relaxed);
+}
+
+// Set by the resume command to let the actuator push the button again after it has given up.
+static ACTUATION_RESUMED: AtomicBool = AtomicBool::new(false);
+
+// Resumes pushing the button after the actuator has given up actuating or resetting the device,
+// without changing the target state.
+pub(crate) fn resume_actuation() {
+    ACTUATION_RESUMED.store(true, Ordering::Relaxed);
+    WAKE_UP.signal(());
+    TARGET_CHANGED.signal(());
+}
+
+// Time of the last contact with the MQTT broker, in ticks, for the dead-man's switch.
+static LAST_BROKER_CONTACT: AtomicU64 = AtomicU64::new(0);
+
+// Records that the MQTT connection is alive. Until the next call, the dead-man's switch will turn
+// the device off after SafetyConfig::deadman.
+pub(crate) fn record_broker_contact(now: Instant) {
+    LAST_BROKER_CONTACT.store(now.as_ticks(), Ordering::Relaxed);
+}
+
+// Safety limits that can be adjusted at runtime, in ticks. Initialized from SafetyConfig when the
+// actuator starts. Zero disables the limit.
+static MAX_ON_TICKS: AtomicU64 = AtomicU64::new(0);
+static DEADMAN_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Allowed range of a nonzero safety limit. Shorter dead-man's switch would fire between two
+// contacts with the broker.
+const MIN_SAFETY_LIMIT: Duration = Duration::from_secs(10);
+const MAX_SAFETY_LIMIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SafetyLimit {
+    MaxOn,
+    Deadman,
+}
+
+impl SafetyLimit {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SafetyLimit::MaxOn => "maxon",
+            SafetyLimit::Deadman => "deadman",
+        }
+    }
+}
+
+// Returns a safety limit of the given number of seconds, if it is within the allowed range; zero
+// disables the limit. Checked in seconds, so that a huge number cannot overflow the ticks.
+pub(crate) fn safety_limit_from_secs(secs: u64) -> Result<Duration, &'static str> {
+    if secs != 0 && !(MIN_SAFETY_LIMIT.as_secs()..=MAX_SAFETY_LIMIT.as_secs()).contains(&secs) {
+        return Err("out of the allowed range [10, 86400]s");
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+// Adjusts a safety limit until the next reboot; zero disables it.
+pub(crate) fn set_safety_limit(limit: SafetyLimit, secs: u64) -> Result<(), &'static str> {
+    let duration = safety_limit_from_secs(secs)?;
+    match limit {
+        SafetyLimit::MaxOn => &MAX_ON_TICKS,
+        SafetyLimit::Deadman => &DEADMAN_TICKS,
+    }
+    .store(duration.as_ticks(), Ordering::Relaxed);
+    Ok(())
+}
+
+// Overrides the target state with Off for safety reasons, publishing the given alert. Returns the
+// new target state.
+async fn force_off(alert: &'static str) -> TargetState {
+    crate::mqtt_alert(alert);
+    let mut target_state = TARGET_STATE.lock().await;
+    *target_state = TargetState::Off;
+    // A boost ending later must not turn the device back on.
+    cancel_boost();
+    TargetState::Off
+}
+
+// Length of the button pulse of the boot self-check. Far below the length of a push the device
+// registers.
+const SELFCHECK_PULSE: Duration = Duration::from_millis(2);
+
+// Result of the button check of the boot self-check; None until it has run.
+static BUTTON_SELFCHECK: BlockingMutex<ThreadModeRawMutex, Cell<Option<bool>>> =
+    BlockingMutex::new(Cell::new(None));
+
+pub(crate) fn button_selfcheck() -> Option<bool> {
+    BUTTON_SELFCHECK.lock(|result| result.get())
+}
+
+// Pulses the button output and checks that the pad follows it, which fails if the pin is shorted.
+async fn check_button(pin: &mut peripherals::PIN_15) -> bool {
+    let mut flex = gpio::Flex::new(pin);
+    // Released before switching to output, so that there is no glitch.
+    flex.set_high();
+    flex.set_as_output();
+    Timer::after(SELFCHECK_PULSE).await;
+    let released = flex.is_high();
+    flex.set_low();
+    Timer::after(SELFCHECK_PULSE).await;
+    let pushed = flex.is_low();
+    flex.set_high();
+    released && pushed
+}
+
 // Period of time after which the device being in unknown state triggers a log message.
 const STATE_WARNING_TIMEOUT: Duration = Duration::from_secs(11);
 // Period of time after which the device being in unknown state triggers an attempt to reset the
 // device.
 const RESET_TIMEOUT: Duration = Duration::from_secs(21);
 
-enum Action {
+// Time for the device to register a push and show the new state.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+// Period of the actuation cycles when no push is needed and the target does not change.
+const IDLE_ACTUATION_PERIOD: Duration = Duration::from_secs(5);
+
+// Additional delay between the actuation cycles in the low-power mode.
+const LOW_POWER_ACTUATION_DELAY: Duration = Duration::from_secs(25);
+
+// Returns until when the actuator may sleep in the low-power mode: LOW_POWER_ACTUATION_DELAY, but
+// not past the time a safety limit turns the device off, or a boost ends. `on_since` is since when
+// the device is on, if it is, and `boost_until` is when the boost ends, if there is one.
+fn low_power_wake_at(
+    now: Instant,
+    on_since: Option<Instant>,
+    max_on: Duration,
+    deadman: Duration,
+    target_state: TargetState,
+    boost_until: Option<Instant>,
+) -> Instant {
+    let mut wake_at = now + LOW_POWER_ACTUATION_DELAY;
+    if let Some(until) = boost_until {
+        wake_at = wake_at.min(until);
+    }
+    if let (Some(since), true) = (on_since, max_on != Duration::from_ticks(0)) {
+        wake_at = wake_at.min(since + max_on);
+    }
+    if deadman != Duration::from_ticks(0) && target_state != TargetState::Off {
+        let last_contact = Instant::from_ticks(LAST_BROKER_CONTACT.load(Ordering::Relaxed));
+        wake_at = wake_at.min(last_contact + deadman);
+    }
+    wake_at
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Action {
     None,
     ShortPush,
     LongPush,
 }
 
-// Returns the action that should be performed on the button to bring the device closer to the
-// target state.
-fn get_action(
+impl Action {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Action::None => "none",
+            Action::ShortPush => "short_push",
+            Action::LongPush => "long_push",
+        }
+    }
+}
+
+// Since when the actuator sees the device in the unknown state, in ticks. Zero if the state is
+// known. Mirrors the actuator's bookkeeping, for what_if().
+static UNKNOWN_STATE_SINCE: AtomicU64 = AtomicU64::new(0);
+
+// Why the actuator does not make the push it has decided on.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Suppression {
+    // It has given up reaching the target, and waits for a command.
+    ActuationFailed,
+    // It has given up resetting the device, and waits for a command.
+    ResetGivenUp,
+    // Someone has operated the device physically, and the pushes are paused.
+    Inhibited,
+    // There were too many pushes within the last minute.
+    RateLimited,
+}
+
+impl Suppression {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Suppression::ActuationFailed => "actuation_failed",
+            Suppression::ResetGivenUp => "reset_giveup",
+            Suppression::Inhibited => "inhibited",
+            Suppression::RateLimited => "rate_limited",
+        }
+    }
+}
+
+// The actuator's bookkeeping that decides whether it pushes the button, besides the device state
+// and the target. Kept by the actuator across its cycles, and mirrored for what_if().
+#[derive(Clone, Copy)]
+struct ActuatorGate {
+    // Since when the device is continuously on, for the max_on safety limit.
+    on_since: Option<Instant>,
+    // The level the power ramp in progress heads for, if any.
+    ramp_step: Option<TargetState>,
+    // Until when the pushes are paused after physical operation.
+    inhibit_until: Option<Instant>,
+    push_rate_limiter: PushRateLimiter,
+    // Pushes made since the target was last reached or changed, and TARGET_GENERATION they count
+    // for.
+    actuation_cycles: u32,
+    actuation_cycles_at: u32,
+    // If the actuator has given up reaching the target, TARGET_GENERATION at that time. Pushing
+    // resumes after a command.
+    actuation_failed_at: Option<u32>,
+    // Consecutive attempts to reset the device from the unknown state.
+    reset_attempts: u32,
+    // If the actuator has given up resetting the device, TARGET_GENERATION at that time. Resetting
+    // resumes after a command.
+    reset_given_up_at: Option<u32>,
+}
+
+impl ActuatorGate {
+    const fn new() -> ActuatorGate {
+        ActuatorGate {
+            on_since: None,
+            ramp_step: None,
+            inhibit_until: None,
+            push_rate_limiter: PushRateLimiter::new(),
+            actuation_cycles: 0,
+            actuation_cycles_at: 0,
+            actuation_failed_at: None,
+            reset_attempts: 0,
+            reset_given_up_at: None,
+        }
+    }
+}
+
+// The actuator's gate as of its last cycle, and the safety config it runs with. None until the
+// actuator has started.
+static ACTUATOR_GATE: BlockingMutex<
+    ThreadModeRawMutex,
+    Cell<(ActuatorGate, Option<&'static SafetyConfig>)>,
+> = BlockingMutex::new(Cell::new((ActuatorGate::new(), None)));
+
+// The limits the actuator enforces in a cycle. Zero if there is no limit, as in SafetyConfig.
+#[derive(Clone, Copy)]
+struct CycleLimits {
+    max_on: Duration,
+    deadman: Duration,
+    last_broker_contact: Instant,
+    max_pushes_per_minute: u32,
+    max_reset_attempts: u32,
+    max_actuation_cycles: u32,
+}
+
+impl CycleLimits {
+    // Returns the limits in effect now: the safety limits as adjusted at runtime, and the push
+    // limits of `safety_config`, if the actuator has started.
+    fn current(safety_config: Option<&SafetyConfig>) -> CycleLimits {
+        CycleLimits {
+            max_on: Duration::from_ticks(MAX_ON_TICKS.load(Ordering::Relaxed)),
+            deadman: Duration::from_ticks(DEADMAN_TICKS.load(Ordering::Relaxed)),
+            last_broker_contact: Instant::from_ticks(LAST_BROKER_CONTACT.load(Ordering::Relaxed)),
+            max_pushes_per_minute: safety_config.map_or(0, |config| config.max_pushes_per_minute),
+            max_reset_attempts: safety_config.map_or(0, |config| config.max_reset_attempts),
+            max_actuation_cycles: safety_config.map_or(0, |config| config.max_actuation_cycles),
+        }
+    }
+}
+
+// What the actuator does in a cycle.
+#[derive(Clone, Copy)]
+pub(crate) struct CyclePlan {
+    // The safety limit that turns the device off, if one is exceeded.
+    pub safety_off: Option<SafetyLimit>,
+    // The target the actuator works towards: the commanded one, Off once a safety limit is
+    // exceeded, or the current step of the power ramp.
+    pub target: TargetState,
+    pub action: Action,
+    // Why the action is not performed, if it is not.
+    pub suppressed: Option<Suppression>,
+}
+
+// Decides what the actuator does in a cycle, without side effects, so that what_if() reports
+// exactly what the actuator does. `target` is the commanded target state, `unknown_state_for` is
+// for how long the state is unknown, if it is, and `generation` is the current TARGET_GENERATION.
+fn plan_cycle(
+    current_state: DeviceState,
+    target: TargetState,
+    unknown_state_for: Duration,
+    generation: u32,
+    gate: &ActuatorGate,
+    limits: &CycleLimits,
+    now: Instant,
+) -> CyclePlan {
+    let exceeded = |limit: Duration, since: Instant| {
+        limit != Duration::from_ticks(0) && now.duration_since(since) > limit
+    };
+    let safety_off = match (current_state, gate.on_since) {
+        (DeviceState::Heating(_) | DeviceState::On(_), Some(since))
+            if exceeded(limits.max_on, since) =>
+        {
+            Some(SafetyLimit::MaxOn)
+        }
+        _ if target != TargetState::Off && exceeded(limits.deadman, limits.last_broker_contact) => {
+            Some(SafetyLimit::Deadman)
+        }
+        _ => None,
+    };
+    let target = match (safety_off, gate.ramp_step) {
+        (Some(_), _) => TargetState::Off,
+        (None, Some(step)) => step,
+        (None, None) => target,
+    };
+    let action = decide_action(current_state, target, unknown_state_for);
+
+    // Safety takes precedence over the person operating the device, and is worth another try of an
+    // actuation that has failed. A command since giving up is worth another try too.
+    let resetting = current_state == DeviceState::Unknown;
+    let actuation_failed =
+        safety_off.is_none() && gate.actuation_failed_at.is_some_and(|at| at == generation);
+    let actuation_cycles = if safety_off.is_none()
+        && gate.actuation_cycles_at == generation
+        && !target.is_reached(current_state)
+    {
+        gate.actuation_cycles
+    } else {
+        0
+    };
+    let reset_given_up = resetting && gate.reset_given_up_at.is_some_and(|at| at == generation);
+    let reset_attempts = if resetting { gate.reset_attempts } else { 0 };
+    let inhibited = safety_off.is_none() && gate.inhibit_until.is_some_and(|until| now < until);
+
+    let suppressed = match action {
+        Action::None => None,
+        _ if actuation_failed => Some(Suppression::ActuationFailed),
+        _ if !resetting
+            && limits.max_actuation_cycles != 0
+            && actuation_cycles >= limits.max_actuation_cycles =>
+        {
+            Some(Suppression::ActuationFailed)
+        }
+        Action::LongPush if reset_given_up => Some(Suppression::ResetGivenUp),
+        Action::LongPush
+            if resetting
+                && limits.max_reset_attempts != 0
+                && reset_attempts >= limits.max_reset_attempts =>
+        {
+            Some(Suppression::ResetGivenUp)
+        }
+        _ if inhibited => Some(Suppression::Inhibited),
+        _ if gate
+            .push_rate_limiter
+            .is_limited(now, limits.max_pushes_per_minute) =>
+        {
+            Some(Suppression::RateLimited)
+        }
+        _ => None,
+    };
+    CyclePlan {
+        safety_off,
+        target,
+        action,
+        suppressed,
+    }
+}
+
+// Returns the current state, the commanded target state, and what the actuator would do on them,
+// without doing it.
+pub(crate) async fn what_if(now: Instant) -> (DeviceState, TargetState, CyclePlan) {
+    let current_state = get_current_state(now);
+    let target_state = get_target_state().await;
+    let unknown_state_for = match UNKNOWN_STATE_SINCE.load(Ordering::Relaxed) {
+        0 => Duration::from_ticks(0),
+        since => now.duration_since(Instant::from_ticks(since)),
+    };
+    let (gate, safety_config) = ACTUATOR_GATE.lock(|gate| gate.get());
+    let plan = plan_cycle(
+        current_state,
+        target_state,
+        unknown_state_for,
+        TARGET_GENERATION.load(Ordering::Relaxed),
+        &gate,
+        &CycleLimits::current(safety_config),
+        now,
+    );
+    (current_state, target_state, plan)
+}
+
+// The actuator's bookkeeping, mirrored for actuator_status().
+#[derive(Clone, Copy)]
+pub(crate) struct ActuatorStatus {
+    // The target state the actuator has last worked towards.
+    pub target: TargetState,
+    // The last push, and when it was made.
+    pub last_push: Option<(Action, Instant)>,
+    // Since when the device is seen in the unknown state.
+    pub unknown_state_since: Option<Instant>,
+    // Until when the pushes are paused after physical operation.
+    pub inhibit_until: Option<Instant>,
+    // Until when the pushes are suppressed by the rate limiter.
+    pub rate_limited_until: Option<Instant>,
+    // Pushes made since the target was last reached or changed.
+    pub actuation_cycles: u32,
+    // If the actuator waits for a command, the alert it has published when giving up.
+    pub given_up: Option<&'static str>,
+}
+
+static ACTUATOR_STATUS: BlockingMutex<ThreadModeRawMutex, Cell<ActuatorStatus>> =
+    BlockingMutex::new(Cell::new(ActuatorStatus {
+        target: TargetState::Off,
+        last_push: None,
+        unknown_state_since: None,
+        inhibit_until: None,
+        rate_limited_until: None,
+        actuation_cycles: 0,
+        given_up: None,
+    }));
+
+// Returns the actuator's bookkeeping as of its last cycle, to explain why it acts or does not.
+pub(crate) fn actuator_status() -> ActuatorStatus {
+    let mut status = ACTUATOR_STATUS.lock(|status| status.get());
+    status.unknown_state_since = match UNKNOWN_STATE_SINCE.load(Ordering::Relaxed) {
+        0 => None,
+        since => Some(Instant::from_ticks(since)),
+    };
+    status
+}
+
+// Limits the number of button pushes within each minute, so that a storm of commands does not
+// stress the device.
+#[derive(Clone, Copy)]
+struct PushRateLimiter {
+    window_start: Instant,
+    pushes: u32,
+    // Whether a push was suppressed in the current window.
+    limited: bool,
+}
+
+impl PushRateLimiter {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    const fn new() -> PushRateLimiter {
+        PushRateLimiter {
+            window_start: Instant::MIN,
+            pushes: 0,
+            limited: false,
+        }
+    }
+
+    // Returns whether a push at `now` would exceed the limit. Zero max_pushes means no limit.
+    fn is_limited(&self, now: Instant, max_pushes: u32) -> bool {
+        now.duration_since(self.window_start) < Self::WINDOW
+            && max_pushes != 0
+            && self.pushes >= max_pushes
+    }
+
+    // Starts a new window if the current one has passed.
+    fn roll(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) >= Self::WINDOW {
+            self.window_start = now;
+            self.pushes = 0;
+            self.limited = false;
+        }
+    }
+
+    // Counts a push made at `now`.
+    fn count(&mut self, now: Instant) {
+        self.roll(now);
+        self.pushes += 1;
+    }
+
+    // Records that a push at `now` was suppressed, and reports it once per window.
+    fn suppress(&mut self, now: Instant, max_pushes: u32) {
+        self.roll(now);
+        if !self.limited {
+            self.limited = true;
+            mqtt_log!(
+                "Safety: more than {} pushes per minute; suppressing pushes",
+                max_pushes
+            );
+            crate::mqtt_alert("push_rate_limited");
+        }
+    }
+
+    // Returns until when the pushes are suppressed, if they are.
+    fn limited_until(&self) -> Option<Instant> {
+        self.limited.then(|| self.window_start + Self::WINDOW)
+    }
+}
+
+// Keeps track of since when the state is unknown in `unknown_state_since`, and returns for how
+// long it is unknown. Zero if the state is known.
+fn track_unknown_state(
     current_state: DeviceState,
-    target_state: TargetState,
     now: Instant,
     unknown_state_since: &mut Option<Instant>,
+) -> Duration {
+    if current_state != DeviceState::Unknown {
+        *unknown_state_since = None;
+        return Duration::from_ticks(0);
+    }
+    let unknown_state_for = unknown_state_duration(
+        unknown_state_since.get_or_insert(now).as_ticks(),
+        now.as_ticks(),
+    );
+    if unknown_state_for > STATE_WARNING_TIMEOUT {
+        match last_unknown_pattern() {
+            Some(pattern) => mqtt_log!(
+                "State actuator: unknown state for {:?}ms; leds={}",
+                unknown_state_for.as_millis(),
+                pattern
+            ),
+            None => mqtt_log!(
+                "State actuator: unknown state for {:?}ms",
+                unknown_state_for.as_millis()
+            ),
+        }
+    }
+    unknown_state_for
+}
+
+// Returns for how long the state is unknown, given since when it is, both in ticks.
+const fn unknown_state_duration(since: u64, now: u64) -> Duration {
+    Duration::from_ticks(now.saturating_sub(since))
+}
+
+// Whether the count of the unknown state starts anew after the action, which is the case after a
+// reset attempt, so that the next attempt is made RESET_TIMEOUT after it.
+const fn restarts_unknown_state(current_state: DeviceState, action: Action) -> bool {
+    matches!(
+        (current_state, action),
+        (DeviceState::Unknown, Action::LongPush)
+    )
+}
+
+// Returns the action that should be performed on the button to bring the device closer to the
+// target state. `unknown_state_for` is for how long the state is unknown, if it is.
+const fn decide_action(
+    current_state: DeviceState,
+    target_state: TargetState,
+    unknown_state_for: Duration,
 ) -> Action {
-    // Convert the current state to the corresponding target state, if possible.
-    let current_state = match current_state {
+    if matches!(current_state, DeviceState::Unknown) {
+        if unknown_state_for.as_ticks() > RESET_TIMEOUT.as_ticks() {
+            // Try to reset the device.
+            return Action::LongPush;
+        }
+        // If the state is unknown for a short period of time, it might be some kind of transition;
+        // just do nothing and hope that the transition will finish by the next actuation cycle.
+        return Action::None;
+    }
+
+    // Only the first press of the plan is made: the actuator observes the device again before the
+    // next one, and plans anew if the device did not react as modelled.
+    let plan = plan_pushes(current_state, target_state);
+    if plan.len == 0 {
+        return Action::None;
+    }
+    match plan.presses[0] {
+        Press::Short => Action::ShortPush,
+        Press::Long => Action::LongPush,
+    }
+}
+
+// The device is reset once the state has been unknown for longer than RESET_TIMEOUT, whatever the
+// target, and the next reset is RESET_TIMEOUT after it.
+const _: () = {
+    let target = TargetState::On(PowerLevel::Medium);
+    let just_past = Duration::from_ticks(RESET_TIMEOUT.as_ticks() + 1);
+    let unknown = DeviceState::Unknown;
+    assert!(matches!(
+        decide_action(unknown, target, Duration::from_ticks(0)),
+        Action::None
+    ));
+    assert!(matches!(
+        decide_action(unknown, target, STATE_WARNING_TIMEOUT),
+        Action::None
+    ));
+    assert!(matches!(
+        decide_action(unknown, target, RESET_TIMEOUT),
+        Action::None
+    ));
+    assert!(matches!(
+        decide_action(unknown, target, just_past),
+        Action::LongPush
+    ));
+    assert!(matches!(
+        decide_action(unknown, TargetState::Off, just_past),
+        Action::LongPush
+    ));
+
+    // The state is unknown since 1000; the reset is made just past RESET_TIMEOUT.
+    let since = 1000;
+    let reset_at = since + just_past.as_ticks();
+    let action = decide_action(unknown, target, unknown_state_duration(since, reset_at));
+    assert!(matches!(action, Action::LongPush));
+    assert!(restarts_unknown_state(unknown, action));
+    // The count restarts on the next cycle, so the next reset is RESET_TIMEOUT away again.
+    let since = reset_at + 1;
+    assert!(matches!(
+        decide_action(unknown, target, unknown_state_duration(since, since)),
+        Action::None
+    ));
+    assert!(matches!(
+        decide_action(
+            unknown,
+            target,
+            unknown_state_duration(since, since + RESET_TIMEOUT.as_ticks())
+        ),
+        Action::None
+    ));
+    assert!(matches!(
+        decide_action(
+            unknown,
+            target,
+            unknown_state_duration(since, since + just_past.as_ticks())
+        ),
+        Action::LongPush
+    ));
+
+    // Only a reset restarts the count.
+    assert!(!restarts_unknown_state(unknown, Action::None));
+    assert!(!restarts_unknown_state(DeviceState::Off, Action::LongPush));
+};
+
+// A press of the device's button.
+#[derive(Clone, Copy)]
+enum Press {
+    Short,
+    Long,
+}
+
+// Model of how the device reacts to button presses. A short press cycles through Low, Medium, High
+// and Off; a long press turns the device off, or on at the lowest level.
+const fn after_press(state: TargetState, press: Press) -> TargetState {
+    match (state, press) {
+        (TargetState::Off, Press::Short) => TargetState::On(PowerLevel::Low),
+        (TargetState::On(PowerLevel::Low), Press::Short) => TargetState::On(PowerLevel::Medium),
+        (TargetState::On(PowerLevel::Medium), Press::Short) => TargetState::On(PowerLevel::High),
+        (TargetState::On(PowerLevel::High), Press::Short) => TargetState::Off,
+        (TargetState::Off, Press::Long) => TargetState::On(PowerLevel::Low),
+        (TargetState::On(_), Press::Long) => TargetState::Off,
+    }
+}
+
+// Number of states in the model, and the longest plan between any two of them.
+const MODEL_STATES: usize = 4;
+const MAX_PLAN_LEN: usize = 3;
+
+const fn state_index(state: TargetState) -> usize {
+    match state {
+        TargetState::Off => 0,
+        TargetState::On(level) => 1 + level as usize,
+    }
+}
+
+// A sequence of presses; only the first `len` are used.
+struct PushPlan {
+    presses: [Press; MAX_PLAN_LEN],
+    len: usize,
+}
+
+// Returns a shortest sequence of presses that brings the device from one state to another, found
+// by a breadth-first search over the model. Short presses are tried first, so on ties the plan
+// prefers them over the slower long presses.
+const fn plan_presses(from: TargetState, to: TargetState) -> PushPlan {
+    // For every reached state, the state and the press it was reached from.
+    let mut reached_from: [Option<(TargetState, Press)>; MODEL_STATES] = [None; MODEL_STATES];
+    let mut visited = [false; MODEL_STATES];
+    let mut queue = [TargetState::Off; MODEL_STATES];
+    let (mut head, mut tail) = (0, 1);
+    queue[0] = from;
+    visited[state_index(from)] = true;
+    while head < tail {
+        let state = queue[head];
+        head += 1;
+        let presses = [Press::Short, Press::Long];
+        let mut i = 0;
+        while i < presses.len() {
+            let next = after_press(state, presses[i]);
+            if !visited[state_index(next)] {
+                visited[state_index(next)] = true;
+                reached_from[state_index(next)] = Some((state, presses[i]));
+                queue[tail] = next;
+                tail += 1;
+            }
+            i += 1;
+        }
+    }
+
+    // Walk back from the target to find the length, and then again to fill the presses in.
+    let mut len = 0;
+    let mut state = to;
+    while state_index(state) != state_index(from) {
+        match reached_from[state_index(state)] {
+            Some((previous, _)) => state = previous,
+            None => panic!("every state is reachable in the model"),
+        }
+        len += 1;
+    }
+    let mut plan = PushPlan {
+        presses: [Press::Short; MAX_PLAN_LEN],
+        len,
+    };
+    let mut state = to;
+    while len > 0 {
+        len -= 1;
+        if let Some((previous, press)) = reached_from[state_index(state)] {
+            plan.presses[len] = press;
+            state = previous;
+        }
+    }
+    plan
+}
+
+// Returns the presses that bring the device from its current state to the target state. A pure
+// function of the states, independent of the hardware and the timing, which are left to the
+// actuator. A level that is heating up counts as reached. The plan for the unknown state is empty,
+// as there is nothing to plan from: decide_action() resets the device if the state stays unknown.
+const fn plan_pushes(current: DeviceState, target: TargetState) -> PushPlan {
+    let from = match current {
         DeviceState::Off => TargetState::Off,
-        DeviceState::Heating(x) | DeviceState::On(x) => TargetState::On(x),
+        DeviceState::Heating(level) | DeviceState::On(level) => TargetState::On(level),
         DeviceState::Unknown => {
-            let unknown_state_for = match *unknown_state_since {
-                Some(x) => now.duration_since(x),
-                None => {
-                    *unknown_state_since = Some(now);
-                    Duration::from_nanos(0)
-                }
-            };
-            if unknown_state_for > STATE_WARNING_TIMEOUT {
-                mqtt_log!(
-                    "State actuator: unknown state for {:?}ms",
-                    unknown_state_for.as_millis()
-                );
-            }
-            if unknown_state_for > RESET_TIMEOUT {
-                // Try to reset the device. Also reset the unknown state timer, so that the next
-                // reset attempt happens in some time.
-                *unknown_state_since = None;
-                return Action::LongPush;
+            return PushPlan {
+                presses: [Press::Short; MAX_PLAN_LEN],
+                len: 0,
             }
-            // If the state is unknown for a short period of time, it might be some kind of
-            // transition; just do nothing and hope that the transition will finish by the next
-            // actuation cycle.
-            return Action::None;
         }
     };
-    // If the code above did not early return, the state is known.
-    *unknown_state_since = None;
+    plan_presses(from, target)
+}
+
+// Checks the plans between every pair of states: their lengths, and that following them through
+// the model reaches the target.
+const _: () = {
+    let states = [
+        TargetState::Off,
+        TargetState::On(PowerLevel::Low),
+        TargetState::On(PowerLevel::Medium),
+        TargetState::On(PowerLevel::High),
+    ];
+    // As [from][to], in the order of `states`.
+    let expected_len = [[0, 1, 2, 3], [1, 0, 1, 2], [1, 2, 0, 1], [1, 2, 3, 0]];
+    let mut from = 0;
+    while from < MODEL_STATES {
+        let mut to = 0;
+        while to < MODEL_STATES {
+            let plan = plan_presses(states[from], states[to]);
+            assert!(plan.len == expected_len[from][to]);
+            let mut state = states[from];
+            let mut i = 0;
+            while i < plan.len {
+                state = after_press(state, plan.presses[i]);
+                i += 1;
+            }
+            assert!(state_index(state) == to);
+            to += 1;
+        }
+        from += 1;
+    }
+};
+
+// Checks plan_pushes() for every observed state and target: it plans from the level shown, both
+// while heating and once heated, and never for the unknown state.
+const _: () = {
+    let levels = [PowerLevel::Low, PowerLevel::Medium, PowerLevel::High];
+    let targets = [
+        TargetState::Off,
+        TargetState::On(PowerLevel::Low),
+        TargetState::On(PowerLevel::Medium),
+        TargetState::On(PowerLevel::High),
+    ];
+    let mut to = 0;
+    while to < targets.len() {
+        assert!(plan_pushes(DeviceState::Unknown, targets[to]).len == 0);
+        assert!(
+            plan_pushes(DeviceState::Off, targets[to]).len
+                == plan_presses(targets[0], targets[to]).len
+        );
+        let mut level = 0;
+        while level < levels.len() {
+            let expected = plan_presses(TargetState::On(levels[level]), targets[to]).len;
+            assert!(plan_pushes(DeviceState::Heating(levels[level]), targets[to]).len == expected);
+            assert!(plan_pushes(DeviceState::On(levels[level]), targets[to]).len == expected);
+            level += 1;
+        }
+        to += 1;
+    }
 
-    match (current_state, target_state) {
-        (x, y) if x == y => Action::None,
-        (TargetState::Off, TargetState::On(_)) | (TargetState::On(_), TargetState::Off) => {
-            Action::LongPush
+    // Turning on from off and off from on take a single press: short to the lowest level, and long
+    // from any level.
+    let plan = plan_pushes(DeviceState::Off, TargetState::On(PowerLevel::Low));
+    assert!(plan.len == 1 && matches!(plan.presses[0], Press::Short));
+    let mut level = 0;
+    while level < levels.len() {
+        let plan = plan_pushes(DeviceState::On(levels[level]), TargetState::Off);
+        assert!(plan.len == 1);
+        // The short press wraps around from the highest level to off, and is preferred there.
+        if matches!(levels[level], PowerLevel::High) {
+            assert!(matches!(plan.presses[0], Press::Short));
+        } else {
+            assert!(matches!(plan.presses[0], Press::Long));
         }
-        // Remaining arm is when both states are TargetState::On, but with different power levels.
-        _ => Action::ShortPush,
+        level += 1;
     }
+
+    // Going down a level wraps around through off: High -> Off -> Low.
+    let plan = plan_pushes(
+        DeviceState::Heating(PowerLevel::High),
+        TargetState::On(PowerLevel::Low),
+    );
+    assert!(plan.len == 2 && matches!(plan.presses, [Press::Short, Press::Short, _]));
+    // Going up a level is a single short press.
+    let plan = plan_pushes(
+        DeviceState::On(PowerLevel::Low),
+        TargetState::On(PowerLevel::Medium),
+    );
+    assert!(plan.len == 1 && matches!(plan.presses[0], Press::Short));
+};
+
+// A power ramp in progress: the device is turned on at the lowest level, and stepped up one level
+// at a time towards `to`, staying at each level for its dwell time.
+struct Ramp {
+    // TARGET_GENERATION when the ramp started; a command arriving later ends it.
+    generation: u32,
+    to: TargetState,
+    // The level the actuator currently heads for.
+    step: TargetState,
+    // When the device has reached `step`.
+    reached_at: Option<Instant>,
 }
 
 #[embassy_executor::task]
-pub(super) async fn state_actuator_task(pin: peripherals::PIN_15) -> ! {
+pub(super) async fn state_actuator_task(
+    mut pin: peripherals::PIN_15,
+    physical_inhibit: Duration,
+    ramp_config: &'static RampConfig,
+    low_power_after: Duration,
+    safety_config: &'static SafetyConfig,
+    selfcheck: bool,
+) -> ! {
+    if selfcheck {
+        let passed = check_button(&mut pin).await;
+        BUTTON_SELFCHECK.lock(|result| result.set(Some(passed)));
+    }
     let mut pin = gpio::Output::new(pin, gpio::Level::High);
+    MAX_ON_TICKS.store(safety_config.max_on.as_ticks(), Ordering::Relaxed);
+    DEADMAN_TICKS.store(safety_config.deadman.as_ticks(), Ordering::Relaxed);
     let mut unknown_state_since = None;
+    let mut gate = ActuatorGate::new();
+    gate.actuation_cycles_at = TARGET_GENERATION.load(Ordering::Relaxed);
+    // The last known state, to detect physical operation of the device.
+    let mut last_known_state: Option<DeviceState> = None;
+    // The last push, and when it was made.
+    let mut last_push: Option<(Action, Instant)> = None;
+    // The power ramp in progress, if any.
+    let mut ramp: Option<Ramp> = None;
 
     loop {
         let now = Instant::now();
+        // Targets set from now on are picked up by the next cycle.
+        TARGET_CHANGED.reset();
+        if let Some(restored) = end_expired_boost(now).await {
+            mqtt_log!("Boost has ended; restoring target {}", restored.as_str());
+        }
         let target_state: TargetState = *TARGET_STATE.lock().await;
-        let current_state = get_current_state(now).await;
+        let current_state = get_current_state(now);
+
+        if current_state != DeviceState::Unknown {
+            if let Some(last) = last_known_state {
+                if physical_inhibit != Duration::from_ticks(0)
+                    && last != current_state
+                    && change_source(last, current_state, now) == ChangeSource::Physical
+                {
+                    mqtt_log!(
+                        "The device was operated physically; pausing pushes for {}s",
+                        physical_inhibit.as_secs()
+                    );
+                    gate.inhibit_until = Some(now + physical_inhibit);
+                }
+            }
+            last_known_state = Some(current_state);
+        }
+
+        match current_state {
+            DeviceState::Off => gate.on_since = None,
+            DeviceState::Heating(_) | DeviceState::On(_) => {
+                let since = gate.on_since.get_or_insert(now);
+                if SAFETY_TIMER_REFRESHED.swap(false, Ordering::Relaxed) {
+                    *since = now;
+                }
+            }
+            // Likely a transition; keep the timer as is.
+            DeviceState::Unknown => {}
+        }
+
+        let generation = TARGET_GENERATION.load(Ordering::Relaxed);
+        if let Some(active) = &ramp {
+            if active.generation != generation || target_state != active.to {
+                mqtt_log!("The target has changed; ending the power ramp");
+                ramp = None;
+            }
+        }
+        if let (true, TargetState::On(PowerLevel::Medium | PowerLevel::High), DeviceState::Off) =
+            (ramp_config.enabled, target_state, current_state)
+        {
+            if ramp.is_none() {
+                mqtt_log!(
+                    "Ramping up to {} one level at a time",
+                    target_state.as_str()
+                );
+                ramp = Some(Ramp {
+                    generation,
+                    to: target_state,
+                    step: TargetState::On(PowerLevel::Low),
+                    reached_at: None,
+                });
+            }
+        }
+        if let Some(active) = &mut ramp {
+            if active.step.is_reached(current_state) {
+                let reached_at = *active.reached_at.get_or_insert(now);
+                let dwell = match active.step {
+                    TargetState::On(PowerLevel::Low) => ramp_config.dwell[0],
+                    _ => ramp_config.dwell[1],
+                };
+                if now.duration_since(reached_at) >= dwell {
+                    active.step = active.step.step_up();
+                    active.reached_at = None;
+                    mqtt_log!("Power ramp: stepping up to {}", active.step.as_str());
+                }
+            }
+            if active.step == active.to {
+                // The rest is regular actuation.
+                ramp = None;
+            }
+        }
+        gate.ramp_step = ramp.as_ref().map(|active| active.step);
+
+        if current_state != DeviceState::Unknown {
+            gate.reset_attempts = 0;
+            gate.reset_given_up_at = None;
+        } else if gate.reset_given_up_at.is_some_and(|at| at != generation) {
+            mqtt_log!("A command has arrived; resuming the attempts to reset the device");
+            gate.reset_attempts = 0;
+            gate.reset_given_up_at = None;
+        }
+        if gate
+            .ramp_step
+            .unwrap_or(target_state)
+            .is_reached(current_state)
+            || gate.actuation_cycles_at != generation
+        {
+            gate.actuation_cycles = 0;
+            gate.actuation_cycles_at = generation;
+        }
+        if gate.actuation_failed_at.is_some_and(|at| at != generation) {
+            mqtt_log!("A command has arrived; resuming the actuation");
+            gate.actuation_failed_at = None;
+        }
+        if ACTUATION_RESUMED.swap(false, Ordering::Relaxed) {
+            mqtt_log!("Resuming the actuation on request");
+            gate.actuation_cycles = 0;
+            gate.actuation_failed_at = None;
+            gate.reset_attempts = 0;
+            gate.reset_given_up_at = None;
+        }
+
+        let unknown_state_for = track_unknown_state(current_state, now, &mut unknown_state_since);
+        let limits = CycleLimits::current(Some(safety_config));
+        let plan = plan_cycle(
+            current_state,
+            target_state,
+            unknown_state_for,
+            generation,
+            &gate,
+            &limits,
+            now,
+        );
+        if restarts_unknown_state(current_state, plan.action) {
+            // Reset the unknown state timer, so that the next reset attempt happens in some time.
+            unknown_state_since = None;
+        }
+        UNKNOWN_STATE_SINCE.store(
+            unknown_state_since.map_or(0, |since| since.as_ticks()),
+            Ordering::Relaxed,
+        );
 
-        match get_action(current_state, target_state, now, &mut unknown_state_since) {
-            Action::None => {}
-            Action::ShortPush => {
+        if let Some(limit) = plan.safety_off {
+            match limit {
+                SafetyLimit::MaxOn => {
+                    mqtt_log!(
+                        "Safety: the device is on for more than {}s; turning it off",
+                        limits.max_on.as_secs()
+                    );
+                    force_off("safety_off").await;
+                    gate.on_since = None;
+                }
+                SafetyLimit::Deadman => {
+                    mqtt_log!(
+                        "Safety: no contact with the broker for more than {}s; turning the device \
+                         off",
+                        limits.deadman.as_secs()
+                    );
+                    force_off("deadman_off").await;
+                }
+            }
+            // Mirrors plan_cycle(), which ignores these once a safety limit is exceeded.
+            gate.inhibit_until = None;
+            gate.actuation_failed_at = None;
+            gate.actuation_cycles = 0;
+            ramp = None;
+            gate.ramp_step = None;
+        }
+
+        let resetting = current_state == DeviceState::Unknown;
+        let pushed = match (plan.action, plan.suppressed) {
+            (Action::None, _) => false,
+            (_, Some(Suppression::ActuationFailed)) => {
+                if gate.actuation_failed_at.is_none() {
+                    mqtt_log!(
+                        "The device has not reached target {} after {} pushes (it is {:?}); \
+                         giving up until a command arrives",
+                        plan.target.as_str(),
+                        gate.actuation_cycles,
+                        current_state
+                    );
+                    crate::mqtt_alert("actuation_failed");
+                    gate.actuation_failed_at = Some(generation);
+                }
+                // Waiting for manual intervention.
+                false
+            }
+            (_, Some(Suppression::ResetGivenUp)) => {
+                if gate.reset_given_up_at.is_none() {
+                    mqtt_log!(
+                        "The device is still in unknown state after {} resets; giving up until a \
+                         command arrives",
+                        gate.reset_attempts
+                    );
+                    crate::mqtt_alert("reset_giveup");
+                    gate.reset_given_up_at = Some(generation);
+                }
+                // Waiting for manual intervention.
+                false
+            }
+            // Someone is operating the device; the target is enforced once they are done.
+            (_, Some(Suppression::Inhibited)) => false,
+            (_, Some(Suppression::RateLimited)) => {
+                gate.push_rate_limiter
+                    .suppress(now, safety_config.max_pushes_per_minute);
+                false
+            }
+            (Action::ShortPush, None) => {
                 mqtt_log!(
                     "Sending short push: current_state: {:?}; target_state: {:?}",
                     current_state,
-                    target_state
+                    plan.target
                 );
+                gate.push_rate_limiter.count(now);
+                gate.actuation_cycles += 1;
+                last_push = Some((Action::ShortPush, now));
+                LAST_PUSH.store(now.as_ticks(), Ordering::Relaxed);
                 pin.set_low();
                 Timer::after_millis(500).await;
                 pin.set_high();
+                true
             }
-            Action::LongPush => {
+            (Action::LongPush, None) => {
                 mqtt_log!(
                     "Sending long push: current_state: {:?}; target_state: {:?}",
                     current_state,
-                    target_state
+                    plan.target
                 );
+                gate.push_rate_limiter.count(now);
+                if resetting {
+                    gate.reset_attempts += 1;
+                } else {
+                    gate.actuation_cycles += 1;
+                }
+                last_push = Some((Action::LongPush, now));
+                LAST_PUSH.store(now.as_ticks(), Ordering::Relaxed);
                 pin.set_low();
                 Timer::after_millis(2000).await;
                 pin.set_high();
+                true
             }
+        };
+        ACTUATOR_GATE.lock(|mirror| mirror.set((gate, Some(safety_config))));
+        ACTUATOR_STATUS.lock(|status| {
+            status.set(ActuatorStatus {
+                target: plan.target,
+                last_push,
+                // Mirrored separately, see actuator_status().
+                unknown_state_since: None,
+                inhibit_until: gate.inhibit_until.filter(|until| now < *until),
+                rate_limited_until: gate.push_rate_limiter.limited_until(),
+                actuation_cycles: gate.actuation_cycles,
+                given_up: if gate.actuation_failed_at.is_some() {
+                    Some("actuation_failed")
+                } else if gate.reset_given_up_at.is_some() {
+                    Some("reset_giveup")
+                } else {
+                    None
+                },
+            })
+        });
+        if pushed {
+            // Give the device some time to settle. A target that arrives meanwhile is handled right
+            // after that.
+            Timer::after(SETTLE_TIME).await;
+        } else {
+            embassy_futures::select::select(
+                Timer::after(IDLE_ACTUATION_PERIOD),
+                TARGET_CHANGED.wait(),
+            )
+            .await;
+        }
+        // In the low-power mode, additionally sleep until a command arrives, the LEDs change, or
+        // some time passes.
+        let now = Instant::now();
+        if is_idle(now, low_power_after) {
+            WAKE_UP.reset();
+            embassy_futures::select::select(
+                Timer::at(low_power_wake_at(
+                    now,
+                    gate.on_since,
+                    limits.max_on,
+                    limits.deadman,
+                    plan.target,
+                    BOOST.lock(|boost| boost.get()).map(|(until, _)| until),
+                )),
+                WAKE_UP.wait(),
+            )
+            .await;
         }
-        // Give the device some time to settle if a button push happened.
-        Timer::after_millis(5000).await;
     }
 }
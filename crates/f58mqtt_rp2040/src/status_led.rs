@@ -0,0 +1,78 @@
+/// Drives the onboard LED of the WiFi chip, so that the connection status is visible on the device.
+use crate::boot;
+use crate::config::{BlinkPattern, StatusLedConfig};
+use crate::init_network::SharedControl;
+use embassy_time::{Duration, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+// Duration of one step of a BlinkPattern.
+const BLINK_STEP: Duration = Duration::from_millis(100);
+
+// Whether the network is joined and has an address.
+static WIFI_UP: AtomicBool = AtomicBool::new(false);
+// Whether the MQTT connection is established.
+static MQTT_CONNECTED: AtomicBool = AtomicBool::new(false);
+// Whether the last attempt to join the network failed.
+static ERROR: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_wifi_up(up: bool) {
+    WIFI_UP.store(up, Ordering::Relaxed);
+}
+
+pub(crate) fn set_mqtt_connected(connected: bool) {
+    MQTT_CONNECTED.store(connected, Ordering::Relaxed);
+}
+
+pub(crate) fn set_error(error: bool) {
+    ERROR.store(error, Ordering::Relaxed);
+}
+
+// Returns the pattern for the current status. Errors take precedence, then the lowest layer that is
+// down.
+fn current_pattern(config: &StatusLedConfig) -> BlinkPattern {
+    if ERROR.load(Ordering::Relaxed) {
+        config.error
+    } else if let (true, Some(phase)) = (config.boot_phases, boot::current_phase()) {
+        boot_phase_pattern(phase)
+    } else if !WIFI_UP.load(Ordering::Relaxed) {
+        config.wifi_connecting
+    } else if !MQTT_CONNECTED.load(Ordering::Relaxed) {
+        config.mqtt_down
+    } else {
+        config.ok
+    }
+}
+
+// Returns a pattern of `phase` short blinks followed by a pause of 1 second.
+fn boot_phase_pattern(phase: u8) -> BlinkPattern {
+    let mut bits = 0;
+    for i in 0..phase as u32 {
+        bits |= 1 << (2 * i);
+    }
+    // There are at most 6 phases, so the pattern is at most 22 steps long.
+    BlinkPattern {
+        bits,
+        len: 2 * phase as u32 + 10,
+    }
+}
+
+#[embassy_executor::task]
+pub(crate) async fn status_led_task(
+    control: &'static SharedControl,
+    config: &'static StatusLedConfig,
+) -> ! {
+    let mut step = 0u32;
+    // The LED level that was last set; None if it was never set.
+    let mut lit: Option<bool> = None;
+    loop {
+        let pattern = current_pattern(config);
+        let on = pattern.bits & (1 << (step % pattern.len)) != 0;
+        // Only talk to the WiFi chip when the level changes: a solid LED needs no traffic at all.
+        if lit != Some(on) {
+            control.lock().await.gpio_set(0, on).await;
+            lit = Some(on);
+        }
+        step = step.wrapping_add(1);
+        Timer::after(BLINK_STEP).await;
+    }
+}